@@ -23,13 +23,13 @@ pub enum Item {
     },
 }
 
-impl ToString for Item {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Item {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Item::Item { item_id, i_name, eng_name, color } => 
-            format!("[Item: {:<04x}] {} ({}) - {}", item_id, eng_name, i_name, color),
+            write!(f, "[Item: {:<04x}] {} ({}) - {}", item_id, eng_name, i_name, color),
             Item::Recipe { recipe_id, i_name, eng_name } => 
-            format!("[Recipe: {:<04x}] {} ({})", recipe_id, eng_name, i_name),
+            write!(f, "[Recipe: {:<04x}] {} ({})", recipe_id, eng_name, i_name),
         }
     }
 }
@@ -51,8 +51,8 @@ impl Item {
 
     pub fn get_name(&self) -> String {
         match self {
-            Item::Item { item_id, i_name, eng_name, color } => eng_name.into(),
-            Item::Recipe { recipe_id, i_name, eng_name } => eng_name.into(),
+            Item::Item { eng_name, .. } => eng_name.into(),
+            Item::Recipe { eng_name, .. } => eng_name.into(),
         }
     }
 }
@@ -61,6 +61,12 @@ pub struct AcnhItems {
     items: Vec<Item>,
 }
 
+impl Default for AcnhItems {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AcnhItems {
     pub fn new() -> Self {
         let mut acnh_items = AcnhItems { items: Vec::new() };
@@ -71,19 +77,19 @@ impl AcnhItems {
     }
 
     pub fn get_item_by_id(&self, query_id: u32) -> Option<Item> {
-        self.items.iter().filter(|i| i.get_type() == ItemType::Item && i.get_id() == query_id).map(|i| i.clone()).nth(0)
+        self.items.iter().find(|i| i.get_type() == ItemType::Item && i.get_id() == query_id).cloned()
     }
 
     pub fn get_recipe_by_id(&self, query_id: u32) -> Option<Item> {
-        self.items.iter().filter(|i| i.get_type() == ItemType::Recipe && i.get_id() == query_id).map(|i| i.clone()).nth(0)
+        self.items.iter().find(|i| i.get_type() == ItemType::Recipe && i.get_id() == query_id).cloned()
     }
 
     pub fn find_item(&self, query: &str) -> Option<Item> {
-        self.find_items(query).iter().filter(|i| i.get_type() == ItemType::Item).map(|i| i.clone()).nth(0)
+        self.find_items(query).iter().find(|i| i.get_type() == ItemType::Item).cloned()
     }
 
     pub fn find_recipe(&self, query: &str) -> Option<Item> {
-        self.find_items(query).iter().filter(|i| i.get_type() == ItemType::Recipe).map(|i| i.clone()).nth(0)
+        self.find_items(query).iter().find(|i| i.get_type() == ItemType::Recipe).cloned()
     }
 
     pub fn find_items(&self, query: &str) -> Vec<Item> {
@@ -95,7 +101,7 @@ impl AcnhItems {
                 }
             }
             true
-        }).map(|i| i.clone()).collect()
+        }).cloned().collect()
     }
 
     fn load_items(&mut self) -> Result<(), Box<dyn Error>> {
@@ -121,7 +127,7 @@ impl AcnhItems {
             let cols = x.split(";").map(|c| c.trim());
             if line_nr == 0 {
                 cols.for_each(|c| {
-                    if c.len() > 0 {
+                    if !c.is_empty() {
                         headers.push(c.into())
                     }
                 });
@@ -0,0 +1,177 @@
+//! Where ACNH keeps its data, per release of the game.
+//!
+//! Offsets move between releases, so they are looked up by the build ID of the
+//! running game. The table uses the same `;`-separated layout as the item
+//! lists, one row per build and one column per structure:
+//!
+//! ```text
+//! build ; version ; inventory ;
+//! 0123456789ABCDEF ; 2.0.0 ; [[main+0x1234560]+0x10]+0x20
+//! ```
+//!
+//! Structures are given as pointer chains, or as plain `heap+`/`main+`
+//! addresses for ones that don't move. A table is bundled with the editor, and
+//! rows in [`USER_OFFSETS_PATH`] add to it or replace bundled rows for the same
+//! build.
+
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use crate::switch_utils::{PointerChain, SwitchError};
+
+/// Rows that take precedence over the bundled table, if the file exists
+pub const USER_OFFSETS_PATH: &str = "./offsets.csv";
+
+const BUNDLED_OFFSETS: &str = include_str!("../csv/offsets.csv");
+
+/// Where each structure lives in one build of the game
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameOffsets {
+    pub build_id: u64,
+    /// Game version the build belongs to, for display
+    pub version: String,
+    /// The 40 inventory slots, two dwords each
+    pub inventory: PointerChain,
+}
+
+/// Known builds and their offsets
+#[derive(Debug, Clone, Default)]
+pub struct OffsetTable {
+    builds: HashMap<u64, GameOffsets>,
+}
+
+impl OffsetTable {
+    /// The table shipped with the editor
+    pub fn bundled() -> Self {
+        BUNDLED_OFFSETS
+            .parse()
+            .expect("Bundled offset table is invalid")
+    }
+
+    /// The bundled table, extended by [`USER_OFFSETS_PATH`] if it exists
+    pub fn with_user_overrides() -> Result<Self, SwitchError> {
+        let mut table = OffsetTable::bundled();
+        if Path::new(USER_OFFSETS_PATH).exists() {
+            table.merge(OffsetTable::load(USER_OFFSETS_PATH)?);
+        }
+        Ok(table)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SwitchError> {
+        crate::switch_utils::parse_file(path)
+    }
+
+    /// Add the builds in `other`, replacing any we already have
+    pub fn merge(&mut self, other: OffsetTable) {
+        self.builds.extend(other.builds);
+    }
+
+    /// Offsets for `build_id`, or an error naming the build if it's unknown
+    pub fn get(&self, build_id: u64) -> Result<&GameOffsets, SwitchError> {
+        self.builds
+            .get(&build_id)
+            .ok_or(SwitchError::UnsupportedBuild { build_id })
+    }
+}
+
+impl FromStr for OffsetTable {
+    type Err = SwitchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate();
+        let headers: Vec<&str> = lines
+            .next()
+            .map(|(_, header)| split_row(header))
+            .unwrap_or_default();
+        let column = |name: &str| {
+            headers.iter().position(|h| *h == name).ok_or_else(|| {
+                SwitchError::InvalidArgument(format!("Offset table has no {:?} column", name))
+            })
+        };
+        let (build, version, inventory) =
+            (column("build")?, column("version")?, column("inventory")?);
+
+        let mut table = OffsetTable::default();
+        for (i, line) in lines {
+            let cols = split_row(line);
+            if cols.is_empty() {
+                continue;
+            }
+            let invalid = |message: String| {
+                SwitchError::InvalidArgument(format!("Line {}: {}", i + 1, message))
+            };
+            let col = |index: usize| cols.get(index).copied().unwrap_or("");
+
+            let build_id = u64::from_str_radix(col(build), 16)
+                .map_err(|_| invalid(format!("Invalid build ID {:?}", col(build))))?;
+            let inventory = col(inventory)
+                .parse()
+                .map_err(|e: SwitchError| invalid(e.to_string()))?;
+            table.builds.insert(
+                build_id,
+                GameOffsets {
+                    build_id,
+                    version: col(version).to_string(),
+                    inventory,
+                },
+            );
+        }
+        Ok(table)
+    }
+}
+
+/// Split a `;`-separated row, dropping the empty column left by a trailing `;`
+fn split_row(line: &str) -> Vec<&str> {
+    let mut cols: Vec<&str> = line.split(';').map(|c| c.trim()).collect();
+    if cols.last() == Some(&"") {
+        cols.pop();
+    }
+    cols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Address, EMULATOR_BUILD_ID};
+
+    #[test]
+    fn bundled_table_knows_the_emulator() {
+        let table = OffsetTable::bundled();
+        let offsets = table.get(EMULATOR_BUILD_ID).unwrap();
+        assert_eq!(offsets.inventory.base, Address::Heap(0xAFB1E6E0));
+        assert_eq!(offsets.inventory.depth(), 0);
+    }
+
+    #[test]
+    fn user_rows_replace_bundled_ones() {
+        let mut table = OffsetTable::bundled();
+        let user: OffsetTable = format!(
+            "build ; version ; inventory ;\n{:016X} ; patched ; [[main+0x100]+0x10]+0x8\n0000000000000001 ; 9.9.9 ; heap+0x40\n",
+            EMULATOR_BUILD_ID
+        )
+        .parse()
+        .unwrap();
+        table.merge(user);
+
+        let offsets = table.get(EMULATOR_BUILD_ID).unwrap();
+        assert_eq!(offsets.version, "patched");
+        assert_eq!(offsets.inventory.to_string(), "[[main+0x100]+0x10]+0x8");
+        assert_eq!(table.get(1).unwrap().version, "9.9.9");
+    }
+
+    #[test]
+    fn unknown_builds_and_bad_rows_are_errors() {
+        assert!(matches!(
+            OffsetTable::bundled().get(0x1234),
+            Err(SwitchError::UnsupportedBuild { build_id: 0x1234 })
+        ));
+
+        let error = "build ; version ; inventory\nXYZ ; 1.0 ; heap+0x10"
+            .parse::<OffsetTable>()
+            .unwrap_err();
+        assert!(error.to_string().contains("Line 2"), "{}", error);
+        assert!("build ; version\n".parse::<OffsetTable>().is_err());
+        assert!("build ; version ; inventory\n01 ; 1.0 ; [heap+0x10"
+            .parse::<OffsetTable>()
+            .is_err());
+    }
+}
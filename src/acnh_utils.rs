@@ -1,11 +1,14 @@
-use std::error::Error;
-
 use crate::acnh_items::{self, AcnhItems, Item};
-use crate::switch_utils::Switch;
-const INVENTORY_OFFSET: u32 = 0xAFB1E6E0;
+use crate::acnh_offsets::{GameOffsets, OffsetTable};
+use crate::switch_utils::{Address, Switch, SwitchError};
+/// Title ID of Animal Crossing: New Horizons
+pub const ACNH_TITLE_ID: u64 = 0x0100_6F80_0232_6000;
+const RECIPE_ITEM_ID: u32 = 0x16A2;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct ACNH {
     switch: Switch,
+    offsets: GameOffsets,
 }
 
 #[derive(Debug)]
@@ -14,24 +17,64 @@ pub struct InventoryItem {
     pub count: u32,
 }
 
-impl ToString for InventoryItem {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for InventoryItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.item {
-            Item::Item { item_id, i_name, eng_name, color } => {
-                return format!("{} x{}", self.item.to_string(), self.count + 1);
-            },
-            Item::Recipe { recipe_id, i_name, eng_name } => {
-                return format!("{}(Recipe)", self.item.to_string());
-            },
+            Item::Item { .. } => write!(f, "{} x{}", self.item, self.count + 1),
+            Item::Recipe { .. } => write!(f, "{}(Recipe)", self.item),
         }
     }
 }
 
 impl ACNH {
-    pub fn new() -> Self {
-        ACNH {
-            switch: Switch::new().expect("Could not connect to switch!"),
-        }
+    /// Connect to ACNH over USB
+    pub fn new() -> Result<Self, SwitchError> {
+        ACNH::with_switch(Switch::new()?)
+    }
+
+    /// Drive ACNH through an already connected `switch`, whatever its
+    /// transport, using the bundled offsets and any user overrides. Fails if
+    /// some other game, or an unknown release of ACNH, is running.
+    pub fn with_switch(switch: Switch) -> Result<Self, SwitchError> {
+        ACNH::with_offsets(switch, &OffsetTable::with_user_overrides()?)
+    }
+
+    /// Like [`ACNH::with_switch`], picking offsets for the running build from `table`
+    pub fn with_offsets(mut switch: Switch, table: &OffsetTable) -> Result<Self, SwitchError> {
+        check_title(&mut switch)?;
+        let offsets = table.get(switch.build_id()?)?.clone();
+        Ok(ACNH { switch, offsets })
+    }
+
+    /// Offsets in use for the running build
+    pub fn offsets(&self) -> &GameOffsets {
+        &self.offsets
+    }
+
+    /// Make sure ACNH is the game running right now. Every write checks this
+    /// first, so switching games mid-session can't get another game poked.
+    pub fn verify_title(&mut self) -> Result<(), SwitchError> {
+        check_title(&mut self.switch)
+    }
+
+    fn inventory_address(&mut self) -> Result<Address, SwitchError> {
+        let chain = self.offsets.inventory.clone();
+        self.switch.resolve_pointer(&chain)
+    }
+
+    fn write(&mut self, writes: &[(Address, Vec<u8>)]) -> Result<(), SwitchError> {
+        self.verify_title()?;
+        self.switch.write_batch(writes)
+    }
+
+    /// Read-only view of the underlying Switch connection
+    pub fn switch(&self) -> &Switch {
+        &self.switch
+    }
+
+    /// The underlying Switch connection, for anything that isn't ACNH specific
+    pub fn switch_mut(&mut self) -> &mut Switch {
+        &mut self.switch
     }
 
     pub fn set_inventory(
@@ -39,7 +82,7 @@ impl ACNH {
         slot: u32,
         item: &Item,
         count: u32,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), SwitchError> {
         match item {
             Item::Item {
                 item_id,
@@ -60,63 +103,59 @@ impl ACNH {
         slot: u32,
         item_id: u32,
         count: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        self.switch
-            .write_dword(INVENTORY_OFFSET + slot * 8, item_id)?;
-        self.switch
-            .write_dword(INVENTORY_OFFSET + slot * 8 + 4, count - 1)?;
-        Ok(())
+    ) -> Result<(), SwitchError> {
+        let inventory = self.inventory_address()?;
+        self.write(&[slot_write(inventory, slot, item_id, count - 1)?])
     }
 
     pub fn set_inventory_recipe(
         &mut self,
         slot: u32,
         recipe_id: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        self.switch
-            .write_dword(INVENTORY_OFFSET + slot * 8, 0x16A2)?;
-        self.switch
-            .write_dword(INVENTORY_OFFSET + slot * 8 + 4, recipe_id)?;
-        Ok(())
+    ) -> Result<(), SwitchError> {
+        let inventory = self.inventory_address()?;
+        self.write(&[slot_write(inventory, slot, RECIPE_ITEM_ID, recipe_id)?])
     }
 
-    pub fn fill_inventory_items(&mut self, item_id: u32, count: u32) -> Result<(), Box<dyn Error>> {
-        for slot in 0..40 {
-            self.set_inventory_item(slot, item_id, count)?;
-        }
-        Ok(())
+    pub fn fill_inventory_items(&mut self, item_id: u32, count: u32) -> Result<(), SwitchError> {
+        let inventory = self.inventory_address()?;
+        let writes = (0..40)
+            .map(|slot| slot_write(inventory, slot, item_id, count - 1))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.write(&writes)
     }
 
-    pub fn fill_inventory_recipes(&mut self, recipe_id: u32) -> Result<(), Box<dyn Error>> {
-        for slot in 0..40 {
-            self.set_inventory_recipe(slot, recipe_id)?;
-        }
-        Ok(())
+    pub fn fill_inventory_recipes(&mut self, recipe_id: u32) -> Result<(), SwitchError> {
+        let inventory = self.inventory_address()?;
+        let writes = (0..40)
+            .map(|slot| slot_write(inventory, slot, RECIPE_ITEM_ID, recipe_id))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.write(&writes)
     }
 
-    pub fn fill_inventory(&mut self, item: &Item, count: u32) -> Result<(), Box<dyn Error>> {
-        for slot in 0..40 {
-            self.set_inventory(slot, &item, count)?;
+    pub fn fill_inventory(&mut self, item: &Item, count: u32) -> Result<(), SwitchError> {
+        match item {
+            Item::Item { item_id, .. } => self.fill_inventory_items(*item_id, count),
+            Item::Recipe { recipe_id, .. } => self.fill_inventory_recipes(*recipe_id),
         }
-        Ok(())
     }
 
-    pub fn clear_inventory(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn clear_inventory(&mut self) -> Result<(), SwitchError> {
         self.fill_inventory_items(0xfffe, 1)
     }
 
-    pub fn get_inventory(&mut self, acnh_items: &AcnhItems) -> Result<Vec<InventoryItem>, Box<dyn Error>> {
+    pub fn get_inventory(&mut self, acnh_items: &AcnhItems) -> Result<Vec<InventoryItem>, SwitchError> {
         let mut inventory: [u8; 320] = [0; 320];
-        self.switch.read_bytes(0xAFB1E6E0, &mut inventory, 320)?;
+        let address = self.inventory_address()?;
+        self.switch.read_bytes(address, &mut inventory, 320)?;
 
         Ok((0..40)
             .map(|i| -> InventoryItem {
                 let a = u32::from_le_bytes(inventory[i * 8..i * 8 + 4].try_into().unwrap());
                 let b = u32::from_le_bytes(inventory[i * 8 + 4..i * 8 + 8].try_into().unwrap());
 
-                if a == 0x16A2 {
-
-                    return InventoryItem {
+                if a == RECIPE_ITEM_ID {
+                    InventoryItem {
                         item: acnh_items
                         .get_recipe_by_id(b)
                         .unwrap_or(acnh_items::Item::Recipe {
@@ -125,9 +164,9 @@ impl ACNH {
                             eng_name: "Unknown".into(),
                         }),
                         count: 1,
-                    };
+                    }
                 } else {
-                    return InventoryItem {
+                    InventoryItem {
                         item: acnh_items
                         .get_item_by_id(a)
                         .unwrap_or(acnh_items::Item::Item {
@@ -137,9 +176,180 @@ impl ACNH {
                             color: "None".into(),
                         }),
                         count: b,
-                    };
+                    }
                 }
             })
             .collect())
     }
 }
+
+fn check_title(switch: &mut Switch) -> Result<(), SwitchError> {
+    let running = switch.title_id()?;
+    if running != ACNH_TITLE_ID {
+        return Err(SwitchError::WrongTitle {
+            expected: ACNH_TITLE_ID,
+            running,
+        });
+    }
+    Ok(())
+}
+
+/// The write that puts the two dwords ACNH stores per item into `slot` of the
+/// inventory at `inventory`
+fn slot_write(
+    inventory: Address,
+    slot: u32,
+    first: u32,
+    second: u32,
+) -> Result<(Address, Vec<u8>), SwitchError> {
+    let mut bytes = first.to_le_bytes().to_vec();
+    bytes.extend(second.to_le_bytes());
+    Ok((inventory.offset(slot as u64 * 8)?, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, EMULATOR_HEAP_BASE, EMULATOR_TITLE_ID};
+
+    /// Where the bundled offset table puts the emulator's inventory
+    const INVENTORY_OFFSET: Address = Address::Heap(0xAFB1E6E0);
+
+    fn emulated_acnh() -> (ACNH, Emulator) {
+        let emulator = Emulator::new();
+        let switch = Switch::with_transport(emulator.clone());
+        let acnh = ACNH::with_offsets(switch, &OffsetTable::bundled()).unwrap();
+        (acnh, emulator)
+    }
+
+    fn slot(emulator: &Emulator, slot: u32) -> (u32, u32) {
+        let raw = emulator.read(INVENTORY_OFFSET.offset(slot as u64 * 8).unwrap(), 8);
+        (
+            u32::from_le_bytes(raw[0..4].try_into().unwrap()),
+            u32::from_le_bytes(raw[4..8].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn get_inventory_decodes_items_and_recipes() {
+        let acnh_items = AcnhItems::new();
+        let (mut acnh, emulator) = emulated_acnh();
+        // Slot 0: 5 baby beds, slot 1: rocking horse recipe
+        emulator.write(INVENTORY_OFFSET, &[0x4f, 0, 0, 0, 4, 0, 0, 0]);
+        emulator.write(INVENTORY_OFFSET.offset(8).unwrap(), &[0xa2, 0x16, 0, 0, 0x66, 0, 0, 0]);
+
+        let inventory = acnh.get_inventory(&acnh_items).unwrap();
+        assert_eq!(inventory.len(), 40);
+        assert_eq!(inventory[0].item.get_id(), 0x4f);
+        assert_eq!(inventory[0].item.get_name(), "baby bed");
+        assert_eq!(inventory[0].count, 4);
+        assert_eq!(inventory[1].item.get_type(), acnh_items::ItemType::Recipe);
+        assert_eq!(inventory[1].item.get_name(), "rocking horse");
+        assert_eq!(inventory[2].item.get_name(), "Unknown");
+    }
+
+    #[test]
+    fn set_inventory_recipe_writes_recipe_marker() {
+        let (mut acnh, emulator) = emulated_acnh();
+        acnh.set_inventory_recipe(3, 0x66).unwrap();
+        assert_eq!(slot(&emulator, 3), (0x16A2, 0x66));
+        assert_eq!(slot(&emulator, 2), (0, 0));
+    }
+
+    #[test]
+    fn clear_inventory_empties_every_slot() {
+        let (mut acnh, emulator) = emulated_acnh();
+        acnh.set_inventory_item(7, 0x4f, 3).unwrap();
+        acnh.clear_inventory().unwrap();
+        for i in 0..40 {
+            assert_eq!(slot(&emulator, i), (0xfffe, 0));
+        }
+    }
+
+    #[test]
+    fn fill_inventory_round_trips() {
+        let acnh_items = AcnhItems::new();
+        let (mut acnh, emulator) = emulated_acnh();
+        let item = acnh_items.get_item_by_id(0x50).unwrap();
+        acnh.fill_inventory(&item, 10).unwrap();
+
+        assert_eq!(emulator.commands().iter().filter(|c| c.starts_with("poke")).count(), 1);
+
+        let inventory = acnh.get_inventory(&acnh_items).unwrap();
+        for inv_item in inventory {
+            assert_eq!(inv_item.item.get_id(), 0x50);
+            assert_eq!(inv_item.count, 9);
+        }
+    }
+
+    #[test]
+    fn verified_set_inventory_reports_lost_writes() {
+        let (mut acnh, emulator) = emulated_acnh();
+        acnh.switch_mut().set_verified_writes(Some(1));
+
+        emulator.lose_pokes(1);
+        acnh.set_inventory_item(5, 0x4f, 3).unwrap();
+        assert_eq!(slot(&emulator, 5), (0x4f, 2));
+
+        emulator.lose_pokes(2);
+        assert!(matches!(
+            acnh.set_inventory_item(6, 0x4f, 3),
+            Err(SwitchError::WriteMismatch { attempts: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn refuses_other_titles() {
+        let emulator = Emulator::new();
+        emulator.set_title(0x0100_0000_0000_1000, 0);
+        assert!(matches!(
+            ACNH::with_offsets(Switch::with_transport(emulator.clone()), &OffsetTable::bundled()),
+            Err(SwitchError::WrongTitle { running: 0x0100_0000_0000_1000, .. })
+        ));
+
+        let (mut acnh, emulator) = emulated_acnh();
+        emulator.set_title(0x0100_0000_0000_1000, 0);
+        assert!(acnh.set_inventory_item(0, 0x4f, 1).is_err());
+        assert_eq!(slot(&emulator, 0), (0, 0));
+    }
+
+    #[test]
+    fn bundled_table_knows_the_emulator() {
+        let emulator = Emulator::new();
+        let acnh = ACNH::with_switch(Switch::with_transport(emulator.clone())).unwrap();
+        assert_eq!(acnh.offsets().version, "emulator");
+        assert_eq!(acnh.offsets().inventory.base, INVENTORY_OFFSET);
+
+        emulator.set_title(EMULATOR_TITLE_ID, 0x1234);
+        assert!(matches!(
+            ACNH::with_switch(Switch::with_transport(emulator)),
+            Err(SwitchError::UnsupportedBuild { build_id: 0x1234 })
+        ));
+    }
+
+    #[test]
+    fn offsets_follow_the_running_build() {
+        let emulator = Emulator::new();
+        emulator.set_title(EMULATOR_TITLE_ID, 0x1234);
+        assert!(matches!(
+            ACNH::with_offsets(Switch::with_transport(emulator.clone()), &OffsetTable::bundled()),
+            Err(SwitchError::UnsupportedBuild { build_id: 0x1234 })
+        ));
+
+        // A build whose inventory sits behind a pointer
+        let mut table = OffsetTable::bundled();
+        table.merge(
+            "build ; version ; inventory ;\n0000000000001234 ; test ; [main+0x100]+0x20\n"
+                .parse()
+                .unwrap(),
+        );
+        emulator.write(Address::Main(0x100), &(EMULATOR_HEAP_BASE + 0x2000).to_le_bytes());
+        let mut acnh = ACNH::with_offsets(Switch::with_transport(emulator.clone()), &table).unwrap();
+        assert_eq!(acnh.offsets().version, "test");
+        acnh.set_inventory_item(1, 0x4f, 2).unwrap();
+        assert_eq!(
+            emulator.read(Address::Heap(0x2028), 8),
+            vec![0x4f, 0, 0, 0, 1, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,490 @@
+//! Runs everything that talks to the console on a background thread, so a GUI
+//! never waits on it.
+//!
+//! Requests go in over a channel and results come back over another. Reads
+//! that are asked for again before the worker gets to them are only done
+//! once, and [`Worker::cancel`] drops everything still queued. A transfer
+//! already in flight can't be interrupted, but multi-step requests stop at the
+//! next step, and a connection that finishes opening after being cancelled is
+//! closed again.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::acnh_items::{AcnhItems, Item};
+use crate::acnh_offsets::GameOffsets;
+use crate::acnh_utils::{InventoryItem, ACNH};
+use crate::dmnt_cheats::CheatFile;
+use crate::memory_viewer::MemoryPage;
+use crate::switch_utils::{
+    self, Connection, ConnectionState, Screenshot, Switch, SwitchError, UsbDeviceInfo,
+};
+
+/// How long the worker waits for a request before checking on the connection
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Something for the worker to do
+#[derive(Debug, Clone)]
+pub enum Request {
+    /// Drop the current console, if any, and connect to another, optionally
+    /// recording the session. `write_retries` is as for
+    /// [`Switch::set_verified_writes`].
+    Connect {
+        connection: Connection,
+        recording: Option<PathBuf>,
+        write_retries: Option<u32>,
+    },
+    /// Change whether writes to the connected console are read back
+    SetVerifiedWrites(Option<u32>),
+    /// Find the USB sysbots that are plugged in. Opening each one to read its
+    /// serial number takes a while, hence doing it here.
+    ListDevices,
+    RefreshInventory,
+    SetInventory {
+        slot: u32,
+        item: Item,
+        amount: u32,
+    },
+    /// Put one item in each slot, starting from the first. Slots given
+    /// `None` are left as they are.
+    SetInventorySlots {
+        items: Vec<Option<Item>>,
+        amount: u32,
+    },
+    FillInventory {
+        item: Item,
+        amount: u32,
+    },
+    ClearInventory,
+    Screenshot,
+    RefreshMemory(MemoryPage),
+    WriteMemory {
+        page: MemoryPage,
+        offset: usize,
+        bytes: Vec<u8>,
+    },
+    RunCheats(CheatFile),
+}
+
+impl Request {
+    /// Requests with the same key only need doing once: the last one queued
+    /// stands in for the others
+    fn coalesce_key(&self) -> Option<u8> {
+        match self {
+            Request::RefreshInventory => Some(0),
+            Request::Screenshot => Some(1),
+            Request::RefreshMemory(_) => Some(2),
+            Request::RunCheats(_) => Some(3),
+            Request::ListDevices => Some(4),
+            _ => None,
+        }
+    }
+
+    /// Whether cancelling drops the request. Listing devices doesn't touch
+    /// the console, so it's left alone.
+    fn is_cancellable(&self) -> bool {
+        !matches!(self, Request::ListDevices)
+    }
+}
+
+/// What came of a request, or news about the connection
+pub enum Response {
+    Connected {
+        connection: Connection,
+        offsets: GameOffsets,
+    },
+    ConnectFailed(String),
+    State(ConnectionState),
+    Devices(Vec<UsbDeviceInfo>),
+    Inventory(Vec<InventoryItem>),
+    Screenshot(Screenshot),
+    /// A memory page after a refresh or write
+    Memory(MemoryPage),
+    /// Running the cheats failed, so they should be turned off
+    CheatsFailed(String),
+    Error(String),
+}
+
+/// Handle onto the worker thread. Dropping it stops the thread once it
+/// finishes what it's doing.
+pub struct Worker {
+    requests: Sender<(u64, Request)>,
+    responses: Receiver<Response>,
+    /// Bumped to cancel everything sent before
+    generation: Arc<AtomicU64>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Worker {
+    /// Start a worker with no console connected
+    pub fn start(items: Arc<AcnhItems>) -> Self {
+        let (requests, request_receiver) = mpsc::channel();
+        let (response_sender, responses) = mpsc::channel();
+        let generation = Arc::new(AtomicU64::new(0));
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let mut session = Session {
+            acnh: None,
+            states: None,
+            items,
+            responses: response_sender,
+            generation: generation.clone(),
+        };
+        let thread_pending = pending.clone();
+        thread::spawn(move || session.run(request_receiver, thread_pending));
+
+        Worker {
+            requests,
+            responses,
+            generation,
+            pending,
+        }
+    }
+
+    /// Queue `request`. Connecting cancels everything queued before it.
+    pub fn send(&self, request: Request) {
+        if matches!(request, Request::Connect { .. }) {
+            self.cancel();
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let generation = self.generation.load(Ordering::SeqCst);
+        if self.requests.send((generation, request)).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Drop every queued request and stop the current one at its next step
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Whether any request is queued or being worked on
+    pub fn is_busy(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) > 0
+    }
+
+    /// Every response since the last call, without blocking
+    pub fn responses(&self) -> impl Iterator<Item = Response> + '_ {
+        self.responses.try_iter()
+    }
+
+    /// Wait up to `timeout` for the next response
+    pub fn next_response(&self, timeout: Duration) -> Option<Response> {
+        self.responses.recv_timeout(timeout).ok()
+    }
+}
+
+/// Keep only the last of each set of requests sharing a coalescing key,
+/// otherwise leaving the order alone
+fn coalesce(requests: Vec<(u64, Request)>) -> Vec<(u64, Request)> {
+    let keys: Vec<Option<u8>> = requests.iter().map(|(_, r)| r.coalesce_key()).collect();
+    requests
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keys[i + 1..].iter().all(|k| k.is_none() || *k != keys[*i]))
+        .map(|(_, request)| request)
+        .collect()
+}
+
+/// The worker thread's side: the console, if connected, and where to report
+struct Session {
+    acnh: Option<ACNH>,
+    states: Option<Receiver<ConnectionState>>,
+    items: Arc<AcnhItems>,
+    responses: Sender<Response>,
+    generation: Arc<AtomicU64>,
+}
+
+impl Session {
+    fn run(&mut self, requests: Receiver<(u64, Request)>, pending: Arc<AtomicUsize>) {
+        loop {
+            let first = match requests.recv_timeout(POLL_INTERVAL) {
+                Ok(request) => request,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.poll_connection();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            let queued: Vec<(u64, Request)> =
+                std::iter::once(first).chain(requests.try_iter()).collect();
+            let count = queued.len();
+            let batch = coalesce(queued);
+            pending.fetch_sub(count - batch.len(), Ordering::SeqCst);
+
+            for (generation, request) in batch {
+                if !request.is_cancellable() || !self.is_cancelled(generation) {
+                    self.handle(request, generation);
+                }
+                pending.fetch_sub(1, Ordering::SeqCst);
+            }
+            self.poll_connection();
+        }
+    }
+
+    fn is_cancelled(&self, generation: u64) -> bool {
+        self.generation.load(Ordering::SeqCst) != generation
+    }
+
+    fn send(&self, response: Response) {
+        let _ = self.responses.send(response);
+    }
+
+    /// Pass on connection state changes, refreshing the inventory after a
+    /// reconnect
+    fn poll_connection(&mut self) {
+        let Some(acnh) = &mut self.acnh else {
+            return;
+        };
+        acnh.switch_mut().poll_connection();
+        let states: Vec<ConnectionState> = self
+            .states
+            .as_ref()
+            .map(|states| states.try_iter().collect())
+            .unwrap_or_default();
+        for state in states {
+            self.send(Response::State(state));
+            if state == ConnectionState::Connected {
+                self.send_inventory();
+            }
+        }
+    }
+
+    fn connect(
+        &mut self,
+        connection: Connection,
+        recording: Option<PathBuf>,
+        write_retries: Option<u32>,
+        generation: u64,
+    ) {
+        // Let go of the current console first, in case we're reopening the same one
+        self.acnh = None;
+        self.states = None;
+        let acnh = Switch::connect(&connection)
+            .and_then(|switch| match &recording {
+                Some(path) => switch.record_to(path),
+                None => Ok(switch),
+            })
+            .and_then(ACNH::with_switch);
+        // Nobody is waiting for this connection any more
+        if self.is_cancelled(generation) {
+            return;
+        }
+        match acnh {
+            Ok(mut acnh) => {
+                acnh.switch_mut().set_verified_writes(write_retries);
+                self.states = Some(acnh.switch_mut().subscribe());
+                self.send(Response::Connected {
+                    connection,
+                    offsets: acnh.offsets().clone(),
+                });
+                self.acnh = Some(acnh);
+                self.send_inventory();
+            }
+            Err(e) => self.send(Response::ConnectFailed(e.to_string())),
+        }
+    }
+
+    fn send_inventory(&mut self) {
+        let Some(acnh) = &mut self.acnh else {
+            return;
+        };
+        let response = match acnh.get_inventory(&self.items) {
+            Ok(inventory) => Response::Inventory(inventory),
+            Err(e) => Response::Error(e.to_string()),
+        };
+        self.send(response);
+    }
+
+    fn handle(&mut self, request: Request, generation: u64) {
+        if let Request::Connect {
+            connection,
+            recording,
+            write_retries,
+        } = request
+        {
+            self.connect(connection, recording, write_retries, generation);
+            return;
+        }
+        if let Request::ListDevices = request {
+            match switch_utils::list_devices() {
+                Ok(devices) => self.send(Response::Devices(devices)),
+                Err(e) => self.send(Response::Error(e.to_string())),
+            }
+            return;
+        }
+        let Some(acnh) = &mut self.acnh else {
+            self.send(Response::Error(SwitchError::Disconnected.to_string()));
+            return;
+        };
+
+        let mut changed_inventory = false;
+        let result = match request {
+            // Handled above, without needing a console
+            Request::Connect { .. } | Request::ListDevices => unreachable!(),
+            Request::SetVerifiedWrites(retries) => {
+                acnh.switch_mut().set_verified_writes(retries);
+                Ok(None)
+            }
+            Request::RefreshInventory => {
+                changed_inventory = true;
+                Ok(None)
+            }
+            Request::SetInventory { slot, item, amount } => {
+                changed_inventory = true;
+                acnh.set_inventory(slot, &item, amount).map(|_| None)
+            }
+            Request::SetInventorySlots { items, amount } => {
+                changed_inventory = true;
+                let mut result = Ok(None);
+                for (slot, item) in items.iter().enumerate() {
+                    if self.generation.load(Ordering::SeqCst) != generation {
+                        break;
+                    }
+                    let Some(item) = item else {
+                        continue;
+                    };
+                    if let Err(e) = acnh.set_inventory(slot as u32, item, amount) {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                result
+            }
+            Request::FillInventory { item, amount } => {
+                changed_inventory = true;
+                acnh.fill_inventory(&item, amount).map(|_| None)
+            }
+            Request::ClearInventory => {
+                changed_inventory = true;
+                acnh.clear_inventory().map(|_| None)
+            }
+            Request::Screenshot => acnh
+                .switch_mut()
+                .screenshot()
+                .map(|s| Some(Response::Screenshot(s))),
+            Request::RefreshMemory(mut page) => page
+                .refresh(acnh.switch_mut())
+                .map(|_| Some(Response::Memory(page))),
+            Request::WriteMemory {
+                mut page,
+                offset,
+                bytes,
+            } => page
+                .write(acnh.switch_mut(), offset, &bytes)
+                .map(|_| Some(Response::Memory(page))),
+            Request::RunCheats(cheats) => match cheats.run(acnh.switch_mut()) {
+                Ok(()) => Ok(None),
+                Err(e) => Ok(Some(Response::CheatsFailed(e.to_string()))),
+            },
+        };
+
+        match result {
+            Ok(Some(response)) => self.send(response),
+            Ok(None) => {}
+            Err(e) => self.send(Response::Error(e.to_string())),
+        }
+        if changed_inventory {
+            self.send_inventory();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::Address;
+
+    #[test]
+    fn coalesces_repeated_reads_only() {
+        let page = MemoryPage::new(Address::Heap(0), 0x10);
+        let requests = vec![
+            (0, Request::RefreshInventory),
+            (0, Request::ClearInventory),
+            (0, Request::RefreshMemory(page.clone())),
+            (0, Request::RefreshInventory),
+            (0, Request::ClearInventory),
+            (1, Request::RefreshMemory(page)),
+        ];
+        let kept: Vec<String> = coalesce(requests)
+            .iter()
+            .map(|(generation, r)| format!("{} {:?}", generation, r.coalesce_key()))
+            .collect();
+        assert_eq!(kept, vec!["0 None", "0 Some(0)", "0 None", "1 Some(2)"]);
+    }
+
+    #[test]
+    fn works_in_the_background() {
+        let items = Arc::new(AcnhItems::new());
+        let item = items.find_item("Wood").unwrap();
+        let worker = Worker::start(items);
+        let next = || {
+            worker
+                .next_response(Duration::from_secs(5))
+                .expect("worker went quiet")
+        };
+
+        worker.send(Request::RefreshInventory);
+        assert!(matches!(next(), Response::Error(_)));
+
+        worker.send(Request::Connect {
+            connection: Connection::Emulator,
+            recording: None,
+            write_retries: None,
+        });
+        assert!(matches!(next(), Response::Connected { .. }));
+        assert!(matches!(next(), Response::Inventory(_)));
+
+        worker.send(Request::SetInventory {
+            slot: 3,
+            item: item.clone(),
+            amount: 5,
+        });
+        match next() {
+            Response::Inventory(inventory) => {
+                assert_eq!(inventory[3].item.get_id(), item.get_id());
+                // ACNH stores one less than the stack size
+                assert_eq!(inventory[3].count, 4);
+            }
+            _ => panic!("Expected the inventory"),
+        }
+    }
+
+    #[test]
+    fn cancelled_connections_are_not_installed() {
+        let worker = Worker::start(Arc::new(AcnhItems::new()));
+        worker.send(Request::Connect {
+            connection: Connection::Emulator,
+            recording: None,
+            write_retries: None,
+        });
+        worker.cancel();
+        assert!(worker.next_response(Duration::from_millis(200)).is_none());
+
+        worker.send(Request::RefreshInventory);
+        assert!(matches!(
+            worker.next_response(Duration::from_secs(5)),
+            Some(Response::Error(_))
+        ));
+    }
+
+    #[test]
+    fn cancelling_keeps_device_listings() {
+        let worker = Worker::start(Arc::new(AcnhItems::new()));
+        worker.send(Request::ListDevices);
+        worker.cancel();
+        // No USB access in some environments, but either way there's an answer
+        assert!(matches!(
+            worker.next_response(Duration::from_secs(5)),
+            Some(Response::Devices(_) | Response::Error(_))
+        ));
+    }
+}
@@ -0,0 +1,740 @@
+//! Atmosphère `dmnt` cheat files, and a small interpreter that runs them over
+//! sysbot.
+//!
+//! A cheat file (`cheats/<build ID>.txt` in Atmosphère's layout) is a list of
+//! named cheats, each a run of 32-bit hex words making up opcodes:
+//!
+//! ```text
+//! {Master code}
+//! 580F0000 01234560
+//!
+//! [99 bells]
+//! 04000000 00ABCDE0 0000270F
+//! ```
+//!
+//! A cheat in braces is the master cheat, which runs before the others. The
+//! opcodes that only need memory access are supported: static writes,
+//! memory conditionals, loops, register loads, pointer-relative
+//! writes and legacy arithmetic. Cheats using anything else (button
+//! conditionals, the newer arithmetic and register store opcodes, the alias and
+//! ASLR regions) are listed but can't be run.
+//!
+//! Atmosphère runs enabled cheats every frame. Here they run whenever
+//! [`CheatFile::run`] is called, so values are only as frozen as the caller
+//! makes them.
+
+use std::{error::Error, fmt, path::Path, str::FromStr};
+
+use crate::switch_utils::{Address, Switch, SwitchError};
+
+/// Directory cheat files are looked for in, one per build of the game
+pub const CHEATS_DIR: &str = "./cheats";
+
+/// Opcodes executed in one run before it's taken to be stuck in a loop
+const MAX_STEPS: usize = 0x10000;
+
+const REGISTER_COUNT: usize = 16;
+
+/// Where a cheat file for `build_id` lives
+pub fn cheat_path(build_id: u64) -> std::path::PathBuf {
+    Path::new(CHEATS_DIR).join(format!("{:016X}.txt", build_id))
+}
+
+/// A cheat file that failed to parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheatParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CheatParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for CheatParseError {}
+
+impl From<CheatParseError> for SwitchError {
+    fn from(e: CheatParseError) -> Self {
+        SwitchError::InvalidArgument(e.to_string())
+    }
+}
+
+/// How a conditional compares memory against its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Condition {
+    fn holds(self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            Condition::Greater => lhs > rhs,
+            Condition::GreaterOrEqual => lhs >= rhs,
+            Condition::Less => lhs < rhs,
+            Condition::LessOrEqual => lhs <= rhs,
+            Condition::Equal => lhs == rhs,
+            Condition::NotEqual => lhs != rhs,
+        }
+    }
+}
+
+/// Legacy arithmetic operations (opcode 7)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Add,
+    Subtract,
+    Multiply,
+    ShiftLeft,
+    ShiftRight,
+}
+
+/// Where opcode 5 loads a register from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadSource {
+    /// A fixed address in the main NSO or heap
+    Memory(Address),
+    /// An offset from the absolute address already in the register
+    Register(u64),
+}
+
+/// One decoded cheat opcode. Widths are in bytes and addresses in the main
+/// NSO or heap are kept relative to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    /// 0: write `value` at `address` plus a register
+    StoreStatic {
+        width: usize,
+        address: Address,
+        offset_register: usize,
+        value: u64,
+    },
+    /// 1: run up to the matching else or end only if memory compares true
+    BeginConditional {
+        width: usize,
+        address: Address,
+        condition: Condition,
+        value: u64,
+    },
+    /// 2, `21000000`
+    Else,
+    /// 2, `20000000`
+    EndConditional,
+    /// 3: set a register to `count`, then run to the matching end that many
+    /// times
+    BeginLoop {
+        register: usize,
+        count: u32,
+    },
+    EndLoop {
+        register: usize,
+    },
+    /// 4
+    LoadStatic {
+        register: usize,
+        value: u64,
+    },
+    /// 5: load a register from memory, zero-extended
+    LoadMemory {
+        width: usize,
+        register: usize,
+        source: LoadSource,
+    },
+    /// 6: write `value` at the absolute address in a register, optionally
+    /// plus another register, then optionally step the register past it
+    StoreToRegisterAddress {
+        width: usize,
+        register: usize,
+        offset_register: Option<usize>,
+        increment: bool,
+        value: u64,
+    },
+    /// 7
+    Arithmetic {
+        width: usize,
+        register: usize,
+        operation: Operation,
+        value: u32,
+    },
+}
+
+/// One named cheat from a cheat file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub enabled: bool,
+    opcodes: Vec<Opcode>,
+    /// Why the cheat can't be run, if it can't
+    unsupported: Option<String>,
+}
+
+impl Cheat {
+    pub fn opcodes(&self) -> &[Opcode] {
+        &self.opcodes
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.unsupported.is_none()
+    }
+
+    /// Why the cheat can't be run, if it can't
+    pub fn unsupported_reason(&self) -> Option<&str> {
+        self.unsupported.as_deref()
+    }
+}
+
+/// Every cheat in one file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheatFile {
+    pub master: Option<Cheat>,
+    pub cheats: Vec<Cheat>,
+}
+
+impl CheatFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SwitchError> {
+        crate::switch_utils::parse_file(path)
+    }
+
+    /// Run the master cheat and then every enabled cheat once, sharing
+    /// registers between them as Atmosphère does. Nothing runs, not even the
+    /// master cheat, unless some cheat is enabled.
+    pub fn run(&self, switch: &mut Switch) -> Result<(), SwitchError> {
+        let enabled: Vec<&Cheat> = self.cheats.iter().filter(|c| c.enabled).collect();
+        if enabled.is_empty() {
+            return Ok(());
+        }
+        let mut vm = Vm::default();
+        for cheat in self.master.iter().chain(enabled) {
+            if let Some(reason) = &cheat.unsupported {
+                return Err(SwitchError::InvalidArgument(format!(
+                    "Cheat {:?} can't be run: {}",
+                    cheat.name, reason
+                )));
+            }
+            vm.run(switch, &cheat.opcodes)?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, name: String, words: &[u32], master: bool) {
+        let (opcodes, unsupported) = match decode(words) {
+            Ok(opcodes) => (opcodes, None),
+            Err(reason) => (Vec::new(), Some(reason)),
+        };
+        let cheat = Cheat {
+            name,
+            enabled: false,
+            opcodes,
+            unsupported,
+        };
+        if master {
+            self.master = Some(cheat);
+        } else {
+            self.cheats.push(cheat);
+        }
+    }
+}
+
+impl FromStr for CheatFile {
+    type Err = CheatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut file = CheatFile::default();
+        // The cheat being read, its words, and whether it's the master cheat
+        let mut current: Option<(String, Vec<u32>, bool)> = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            let invalid = |message: String| CheatParseError {
+                line: i + 1,
+                message,
+            };
+            if line.is_empty() {
+                continue;
+            }
+            let header = [('[', ']', false), ('{', '}', true)]
+                .into_iter()
+                .find(|(open, _, _)| line.starts_with(*open));
+            if let Some((_, close, master)) = header {
+                let name = line[1..]
+                    .strip_suffix(close)
+                    .ok_or_else(|| invalid(format!("Unclosed cheat name {:?}", line)))?;
+                if master && (file.master.is_some() || current.as_ref().is_some_and(|c| c.2)) {
+                    return Err(invalid("More than one master cheat".into()));
+                }
+                if let Some((name, words, master)) = current.take() {
+                    file.push(name, &words, master);
+                }
+                current = Some((name.trim().to_string(), Vec::new(), master));
+                continue;
+            }
+
+            let (_, words, _) = current
+                .as_mut()
+                .ok_or_else(|| invalid("Opcodes before the first cheat name".into()))?;
+            for word in line.split_whitespace() {
+                if word.len() != 8 {
+                    return Err(invalid(format!(
+                        "Opcode words are 8 hex digits, not {:?}",
+                        word
+                    )));
+                }
+                words.push(
+                    u32::from_str_radix(word, 16)
+                        .map_err(|_| invalid(format!("Invalid opcode word {:?}", word)))?,
+                );
+            }
+        }
+        if let Some((name, words, master)) = current {
+            file.push(name, &words, master);
+        }
+        Ok(file)
+    }
+}
+
+/// Decode a cheat's words into opcodes, or say why they can't be
+fn decode(words: &[u32]) -> Result<Vec<Opcode>, String> {
+    let mut words = words.iter().copied();
+    let mut opcodes = Vec::new();
+    while let Some(first) = words.next() {
+        let mut next = || {
+            words
+                .next()
+                .ok_or_else(|| format!("Opcode {:08X} is cut short", first))
+        };
+        let nibble = |index: u32| ((first >> (28 - 4 * index)) & 0xF) as usize;
+        let width = || match nibble(1) {
+            width @ (1 | 2 | 4 | 8) => Ok(width),
+            width => Err(format!("Invalid width {} in {:08X}", width, first)),
+        };
+        let region = |offset: u64| match nibble(2) {
+            0 => Ok(Address::Main(offset)),
+            1 => Ok(Address::Heap(offset)),
+            2 => Err("the alias region isn't supported".to_string()),
+            3 => Err("the ASLR region isn't supported".to_string()),
+            other => Err(format!("Invalid memory region {} in {:08X}", other, first)),
+        };
+        // 40-bit addresses: the low byte of the first word, then a full word
+        let mut address = || next().map(|low| ((first as u64 & 0xFF) << 32) | low as u64);
+
+        let opcode = match nibble(0) {
+            0x0 => {
+                let (width, offset) = (width()?, address()?);
+                Opcode::StoreStatic {
+                    width,
+                    address: region(offset)?,
+                    offset_register: nibble(3),
+                    value: read_value(width, &mut next)?,
+                }
+            }
+            0x1 => {
+                let (width, offset) = (width()?, address()?);
+                let condition = match nibble(3) {
+                    1 => Condition::Greater,
+                    2 => Condition::GreaterOrEqual,
+                    3 => Condition::Less,
+                    4 => Condition::LessOrEqual,
+                    5 => Condition::Equal,
+                    6 => Condition::NotEqual,
+                    other => return Err(format!("Invalid condition {} in {:08X}", other, first)),
+                };
+                Opcode::BeginConditional {
+                    width,
+                    address: region(offset)?,
+                    condition,
+                    value: read_value(width, &mut next)?,
+                }
+            }
+            0x2 => match nibble(1) {
+                0 => Opcode::EndConditional,
+                1 => Opcode::Else,
+                _ => return Err(format!("Invalid conditional end {:08X}", first)),
+            },
+            0x3 => match nibble(1) {
+                0 => Opcode::BeginLoop {
+                    register: nibble(3),
+                    count: next()?,
+                },
+                1 => Opcode::EndLoop {
+                    register: nibble(3),
+                },
+                _ => return Err(format!("Invalid loop opcode {:08X}", first)),
+            },
+            0x4 => Opcode::LoadStatic {
+                register: nibble(3),
+                value: read_value(8, &mut next)?,
+            },
+            0x5 => {
+                let (width, offset) = (width()?, address()?);
+                let source = match nibble(4) {
+                    0 => LoadSource::Memory(region(offset)?),
+                    1 => LoadSource::Register(offset),
+                    _ => return Err(format!("Unsupported load {:08X}", first)),
+                };
+                Opcode::LoadMemory {
+                    width,
+                    register: nibble(3),
+                    source,
+                }
+            }
+            0x6 => Opcode::StoreToRegisterAddress {
+                width: width()?,
+                register: nibble(3),
+                increment: nibble(4) != 0,
+                offset_register: (nibble(5) != 0).then(|| nibble(6)),
+                value: read_value(8, &mut next)?,
+            },
+            0x7 => {
+                let operation = match nibble(4) {
+                    0 => Operation::Add,
+                    1 => Operation::Subtract,
+                    2 => Operation::Multiply,
+                    3 => Operation::ShiftLeft,
+                    4 => Operation::ShiftRight,
+                    other => return Err(format!("Invalid operation {} in {:08X}", other, first)),
+                };
+                Opcode::Arithmetic {
+                    width: width()?,
+                    register: nibble(3),
+                    operation,
+                    value: next()?,
+                }
+            }
+            0x8 => return Err("button conditionals need controller input".into()),
+            other => return Err(format!("opcode type {:X} isn't supported", other)),
+        };
+        opcodes.push(opcode);
+    }
+    Ok(opcodes)
+}
+
+/// An immediate value: one word, or two (high word first) for 8-byte values
+fn read_value<F: FnMut() -> Result<u32, String>>(
+    width: usize,
+    next: &mut F,
+) -> Result<u64, String> {
+    let first = next()? as u64;
+    if width == 8 {
+        Ok((first << 32) | next()? as u64)
+    } else {
+        Ok(first)
+    }
+}
+
+/// Registers and loop state for one run of the cheats
+#[derive(Default)]
+struct Vm {
+    registers: [u64; REGISTER_COUNT],
+    loop_tops: [usize; REGISTER_COUNT],
+}
+
+impl Vm {
+    fn run(&mut self, switch: &mut Switch, opcodes: &[Opcode]) -> Result<(), SwitchError> {
+        let mut pc = 0;
+        let mut steps = 0;
+        while let Some(opcode) = opcodes.get(pc) {
+            steps += 1;
+            if steps > MAX_STEPS {
+                return Err(SwitchError::InvalidArgument(format!(
+                    "Cheat didn't finish within {} opcodes",
+                    MAX_STEPS
+                )));
+            }
+            pc += 1;
+            match *opcode {
+                Opcode::StoreStatic {
+                    width,
+                    address,
+                    offset_register,
+                    value,
+                } => {
+                    let address = address.with_value(
+                        address
+                            .value()
+                            .wrapping_add(self.registers[offset_register]),
+                    );
+                    write_value(switch, address, width, value)?;
+                }
+                Opcode::BeginConditional {
+                    width,
+                    address,
+                    condition,
+                    value,
+                } => {
+                    if !condition.holds(read_value_at(switch, address, width)?, value) {
+                        pc = skip_block(opcodes, pc, true);
+                    }
+                }
+                // Reached the end of a true branch
+                Opcode::Else => pc = skip_block(opcodes, pc, false),
+                Opcode::EndConditional => {}
+                // Counting down from zero would wrap, so zero runs nothing
+                Opcode::BeginLoop { register, count: 0 } => pc = skip_loop(opcodes, pc, register),
+                Opcode::BeginLoop { register, count } => {
+                    self.registers[register] = count as u64;
+                    self.loop_tops[register] = pc;
+                }
+                Opcode::EndLoop { register } => {
+                    self.registers[register] = self.registers[register].wrapping_sub(1);
+                    if self.registers[register] != 0 {
+                        pc = self.loop_tops[register];
+                    }
+                }
+                Opcode::LoadStatic { register, value } => self.registers[register] = value,
+                Opcode::LoadMemory {
+                    width,
+                    register,
+                    source,
+                } => {
+                    let address = match source {
+                        LoadSource::Memory(address) => address,
+                        LoadSource::Register(offset) => {
+                            Address::Absolute(self.registers[register].wrapping_add(offset))
+                        }
+                    };
+                    self.registers[register] = read_value_at(switch, address, width)?;
+                }
+                Opcode::StoreToRegisterAddress {
+                    width,
+                    register,
+                    offset_register,
+                    increment,
+                    value,
+                } => {
+                    let offset = offset_register.map_or(0, |r| self.registers[r]);
+                    let address = Address::Absolute(self.registers[register].wrapping_add(offset));
+                    write_value(switch, address, width, value)?;
+                    if increment {
+                        self.registers[register] =
+                            self.registers[register].wrapping_add(width as u64);
+                    }
+                }
+                Opcode::Arithmetic {
+                    width,
+                    register,
+                    operation,
+                    value,
+                } => {
+                    let current = self.registers[register];
+                    let value = value as u64;
+                    let result = match operation {
+                        Operation::Add => current.wrapping_add(value),
+                        Operation::Subtract => current.wrapping_sub(value),
+                        Operation::Multiply => current.wrapping_mul(value),
+                        Operation::ShiftLeft => current.checked_shl(value as u32).unwrap_or(0),
+                        Operation::ShiftRight => current.checked_shr(value as u32).unwrap_or(0),
+                    };
+                    self.registers[register] = result & width_mask(width);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Index just past the else or end that closes the conditional before `pc`.
+/// Stops at an else only if `to_else`, to run the false branch.
+fn skip_block(opcodes: &[Opcode], mut pc: usize, to_else: bool) -> usize {
+    let mut depth = 0;
+    while let Some(opcode) = opcodes.get(pc) {
+        pc += 1;
+        match opcode {
+            Opcode::BeginConditional { .. } => depth += 1,
+            Opcode::Else if depth == 0 && to_else => break,
+            Opcode::EndConditional if depth == 0 => break,
+            Opcode::EndConditional => depth -= 1,
+            _ => {}
+        }
+    }
+    pc
+}
+
+/// Index just past the end of the loop on `register` whose body starts at `pc`
+fn skip_loop(opcodes: &[Opcode], mut pc: usize, register: usize) -> usize {
+    let mut depth = 0;
+    while let Some(opcode) = opcodes.get(pc) {
+        pc += 1;
+        match *opcode {
+            Opcode::BeginLoop { register: r, .. } if r == register => depth += 1,
+            Opcode::EndLoop { register: r } if r == register => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    pc
+}
+
+fn width_mask(width: usize) -> u64 {
+    u64::MAX >> (64 - 8 * width as u32)
+}
+
+fn write_value(
+    switch: &mut Switch,
+    address: Address,
+    width: usize,
+    value: u64,
+) -> Result<(), SwitchError> {
+    switch.write_bytes(address, &value.to_le_bytes()[..width])
+}
+
+fn read_value_at(switch: &mut Switch, address: Address, width: usize) -> Result<u64, SwitchError> {
+    let mut buf = [0u8; 8];
+    switch.read_bytes(address, &mut buf, width as u32)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, EMULATOR_HEAP_BASE};
+
+    const CHEATS: &str = "
+        {Master}
+        580F0000 00000100
+
+        [Static write]
+        04000000 00000200 0000270F
+        [Pointer write]
+        580F1000 00000010
+        780F0000 00000008
+        640F0000 00000000 DEADBEEF
+        [Conditional]
+        14150000 00000300 00000001
+        04000000 00000304 00000011
+        21000000
+        04000000 00000304 00000022
+        20000000
+
+        [Needs buttons]
+        80000001
+        04000000 00000200 00000001
+        20000000
+    ";
+
+    #[test]
+    fn parses_sections_and_opcodes() {
+        let file: CheatFile = CHEATS.parse().unwrap();
+        assert_eq!(file.master.as_ref().unwrap().name, "Master");
+        let names: Vec<&str> = file.cheats.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Static write",
+                "Pointer write",
+                "Conditional",
+                "Needs buttons"
+            ]
+        );
+        assert_eq!(
+            file.cheats[0].opcodes(),
+            &[Opcode::StoreStatic {
+                width: 4,
+                address: Address::Main(0x200),
+                offset_register: 0,
+                value: 9999
+            }]
+        );
+        assert!(file.cheats.iter().take(3).all(Cheat::is_supported));
+        assert!(!file.cheats[3].is_supported());
+
+        assert_eq!(
+            "04000000 00000200 0000270F".parse::<CheatFile>().unwrap_err().line,
+            1
+        );
+        assert_eq!("[Bad]\n0400000".parse::<CheatFile>().unwrap_err().line, 2);
+        assert!("[Unclosed\n".parse::<CheatFile>().is_err());
+        let cut_short: CheatFile = "[Short]\n04000000 00000200".parse().unwrap();
+        assert!(!cut_short.cheats[0].is_supported());
+    }
+
+    #[test]
+    fn runs_master_and_enabled_cheats() {
+        let emulator = Emulator::new();
+        // main+0x100 -> heap+0x5000, whose +0x10 -> heap+0x6000
+        emulator.write(
+            Address::Main(0x100),
+            &(EMULATOR_HEAP_BASE + 0x5000).to_le_bytes(),
+        );
+        emulator.write(
+            Address::Heap(0x5010),
+            &(EMULATOR_HEAP_BASE + 0x6000).to_le_bytes(),
+        );
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        let mut file: CheatFile = CHEATS.parse().unwrap();
+        file.run(&mut switch).unwrap();
+        assert!(
+            emulator.commands().is_empty(),
+            "nothing runs until a cheat is enabled"
+        );
+
+        file.cheats[0].enabled = true;
+        file.cheats[1].enabled = true;
+        file.run(&mut switch).unwrap();
+        assert_eq!(
+            emulator.read(Address::Main(0x200), 4),
+            9999u32.to_le_bytes()
+        );
+        assert_eq!(
+            emulator.read(Address::Heap(0x6008), 4),
+            0xDEADBEEFu32.to_le_bytes()
+        );
+
+        file.cheats[3].enabled = true;
+        assert!(file.run(&mut switch).is_err());
+        file.cheats[3].enabled = false;
+
+        // The conditional's false branch, then its true branch
+        file.cheats[2].enabled = true;
+        file.run(&mut switch).unwrap();
+        assert_eq!(
+            emulator.read(Address::Main(0x304), 4),
+            0x22u32.to_le_bytes()
+        );
+        emulator.write(Address::Heap(0x300), &[1]);
+        file.run(&mut switch).unwrap();
+        assert_eq!(
+            emulator.read(Address::Main(0x304), 4),
+            0x11u32.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn loops_repeat_their_body() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+        // r1 = heap+0x1000 (absolute); write 0x7F and step on, three times
+        let mut file: CheatFile = format!(
+            "[Fill]\n40010000 {:08X} {:08X}\n30020000 00000003\n61011000 00000000 0000007F\n31020000\n",
+            (EMULATOR_HEAP_BASE + 0x1000) >> 32,
+            (EMULATOR_HEAP_BASE + 0x1000) & 0xFFFF_FFFF
+        )
+        .parse()
+        .unwrap();
+        file.cheats[0].enabled = true;
+        file.run(&mut switch).unwrap();
+        assert_eq!(
+            emulator.read(Address::Heap(0x1000), 4),
+            vec![0x7F, 0x7F, 0x7F, 0]
+        );
+
+        // A count of zero skips the body rather than wrapping round
+        let mut file: CheatFile =
+            "[Never]\n30020000 00000000\n04100000 00002000 00000001\n31020000\n04100000 00002004 00000002\n"
+                .parse()
+                .unwrap();
+        file.cheats[0].enabled = true;
+        file.run(&mut switch).unwrap();
+        assert_eq!(emulator.read(Address::Heap(0x2000), 8), vec![0, 0, 0, 0, 2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,490 @@
+//! Timed controller input macros.
+//!
+//! A macro is a text file with one step per line:
+//!
+//! ```text
+//! # Open the inventory and scroll down twice
+//! X 100ms                 # hold X for 100ms
+//! wait 1s
+//! DDOWN                   # click DDOWN
+//! press ZL                # hold ZL until released
+//! stick L 0 32767 500ms   # push the left stick up for half a second
+//! stick R -32768 0        # leave the right stick pushed left
+//! touch 640 360
+//! release ZL
+//! ```
+//!
+//! Durations are written as `<number>ms` or `<number>s` (e.g. `1.5s`).
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::switch_utils::{Button, Stick, Switch, SwitchError, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Longest we sleep before checking for cancellation again
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Press and release a button
+    Click(Button),
+    /// Hold a button for a duration, then release it
+    Hold(Button, Duration),
+    /// Press a button and leave it held
+    Press(Button),
+    /// Release a held button
+    Release(Button),
+    /// Move a stick, optionally returning it to centre after a duration
+    Stick {
+        stick: Stick,
+        x: i32,
+        y: i32,
+        duration: Option<Duration>,
+    },
+    /// Tap the touch screen
+    Touch(u32, u32),
+    /// Do nothing for a while
+    Wait(Duration),
+}
+
+/// A macro file that failed to parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MacroParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for MacroParseError {}
+
+impl From<MacroParseError> for SwitchError {
+    fn from(e: MacroParseError) -> Self {
+        SwitchError::InvalidArgument(e.to_string())
+    }
+}
+
+/// How a macro run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroOutcome {
+    Completed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputMacro {
+    pub steps: Vec<Step>,
+}
+
+impl InputMacro {
+    /// Load and parse a macro file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SwitchError> {
+        crate::switch_utils::parse_file(path)
+    }
+
+    /// Total time the macro spends holding and waiting
+    pub fn duration(&self) -> Duration {
+        self.steps.iter().map(step_duration).sum()
+    }
+
+    /// Run the macro against `switch`, stopping early once `cancel` is set.
+    ///
+    /// Waits are scheduled relative to the start of the run, so command
+    /// latency doesn't accumulate into drift. Holds are timed from when the
+    /// press went through, so they last their full duration however late it
+    /// was. Buttons left held and sticks left pushed are reset when the macro
+    /// is cancelled or fails.
+    pub fn run(&self, switch: &mut Switch, cancel: &AtomicBool) -> Result<MacroOutcome, SwitchError> {
+        let mut state = RunState::default();
+        let result = self.run_steps(switch, cancel, &mut state);
+        if !matches!(result, Ok(MacroOutcome::Completed)) {
+            // Best effort; the original outcome matters more than a reset failure
+            let _ = state.reset(switch);
+        }
+        result
+    }
+
+    fn run_steps(
+        &self,
+        switch: &mut Switch,
+        cancel: &AtomicBool,
+        state: &mut RunState,
+    ) -> Result<MacroOutcome, SwitchError> {
+        let mut deadline = Instant::now();
+        for step in &self.steps {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(MacroOutcome::Cancelled);
+            }
+
+            match *step {
+                Step::Click(button) => switch.click(button)?,
+                Step::Hold(button, duration) => {
+                    switch.press(button)?;
+                    state.held.insert(button);
+                    deadline = deadline.max(Instant::now()) + duration;
+                    if !sleep_until(deadline, cancel) {
+                        return Ok(MacroOutcome::Cancelled);
+                    }
+                    switch.release(button)?;
+                    state.held.remove(&button);
+                }
+                Step::Press(button) => {
+                    switch.press(button)?;
+                    state.held.insert(button);
+                }
+                Step::Release(button) => {
+                    switch.release(button)?;
+                    state.held.remove(&button);
+                }
+                Step::Stick { stick, x, y, duration } => {
+                    switch.set_stick(stick, x, y)?;
+                    state.moved.insert(stick);
+                    if let Some(duration) = duration {
+                        deadline = deadline.max(Instant::now()) + duration;
+                        if !sleep_until(deadline, cancel) {
+                            return Ok(MacroOutcome::Cancelled);
+                        }
+                        switch.set_stick(stick, 0, 0)?;
+                        state.moved.remove(&stick);
+                    }
+                }
+                Step::Touch(x, y) => switch.touch(x, y)?,
+                Step::Wait(duration) => {
+                    deadline += duration;
+                    if !sleep_until(deadline, cancel) {
+                        return Ok(MacroOutcome::Cancelled);
+                    }
+                }
+            }
+        }
+        Ok(MacroOutcome::Completed)
+    }
+}
+
+impl FromStr for InputMacro {
+    type Err = MacroParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let step = parse_step(line).map_err(|message| MacroParseError {
+                line: i + 1,
+                message,
+            })?;
+            steps.push(step);
+        }
+        Ok(InputMacro { steps })
+    }
+}
+
+/// Controller state a macro has left behind, so it can be undone
+#[derive(Default)]
+struct RunState {
+    held: HashSet<Button>,
+    moved: HashSet<Stick>,
+}
+
+impl RunState {
+    fn reset(&mut self, switch: &mut Switch) -> Result<(), SwitchError> {
+        for button in self.held.drain() {
+            switch.release(button)?;
+        }
+        for stick in self.moved.drain() {
+            switch.set_stick(stick, 0, 0)?;
+        }
+        Ok(())
+    }
+}
+
+fn step_duration(step: &Step) -> Duration {
+    match step {
+        Step::Hold(_, duration) | Step::Wait(duration) => *duration,
+        Step::Stick { duration, .. } => duration.unwrap_or_default(),
+        _ => Duration::ZERO,
+    }
+}
+
+/// Sleep until `deadline`, returning false if cancelled first
+fn sleep_until(deadline: Instant, cancel: &AtomicBool) -> bool {
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep((deadline - now).min(CANCEL_POLL_INTERVAL));
+    }
+}
+
+fn parse_step(line: &str) -> Result<Step, String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["wait", duration] => Ok(Step::Wait(parse_duration(duration)?)),
+        ["press", button] => Ok(Step::Press(parse_button(button)?)),
+        ["release", button] => Ok(Step::Release(parse_button(button)?)),
+        ["touch", x, y] => {
+            let x = parse_int(x)?;
+            let y = parse_int(y)?;
+            if !(0..SCREEN_WIDTH as i64).contains(&x) || !(0..SCREEN_HEIGHT as i64).contains(&y) {
+                return Err(format!(
+                    "Touch position ({}, {}) is off the {}x{} screen",
+                    x, y, SCREEN_WIDTH, SCREEN_HEIGHT
+                ));
+            }
+            Ok(Step::Touch(x as u32, y as u32))
+        }
+        ["stick", stick, x, y, rest @ ..] if rest.len() <= 1 => Ok(Step::Stick {
+            stick: stick.parse().map_err(|e: SwitchError| e.to_string())?,
+            x: parse_axis(x)?,
+            y: parse_axis(y)?,
+            duration: rest.first().map(|d| parse_duration(d)).transpose()?,
+        }),
+        [button] => Ok(Step::Click(parse_button(button)?)),
+        [button, duration] => Ok(Step::Hold(parse_button(button)?, parse_duration(duration)?)),
+        _ => Err(format!("Can't understand {:?}", line)),
+    }
+}
+
+fn parse_button(arg: &str) -> Result<Button, String> {
+    arg.parse().map_err(|e: SwitchError| e.to_string())
+}
+
+fn parse_int(arg: &str) -> Result<i64, String> {
+    arg.parse().map_err(|_| format!("Invalid number {:?}", arg))
+}
+
+fn parse_axis(arg: &str) -> Result<i32, String> {
+    let value = parse_int(arg)?;
+    if !(i16::MIN as i64..=i16::MAX as i64).contains(&value) {
+        return Err(format!(
+            "Stick axis {} out of range {}..={}",
+            value,
+            i16::MIN,
+            i16::MAX
+        ));
+    }
+    Ok(value as i32)
+}
+
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration {:?} (expected e.g. 100ms or 2s)", arg);
+    let (value, scale) = if let Some(ms) = arg.strip_suffix("ms") {
+        (ms, 1e-3)
+    } else if let Some(s) = arg.strip_suffix('s') {
+        (s, 1.0)
+    } else {
+        return Err(invalid());
+    };
+    let value: f64 = value.parse().map_err(|_| invalid())?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs_f64(value * scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, Transport};
+    use std::sync::{Arc, Mutex};
+
+    /// An emulator that takes `latency` to accept each command, noting when
+    /// each one was sent
+    struct SlowTransport {
+        emulator: Emulator,
+        latency: Duration,
+        sent: Arc<Mutex<Vec<(String, Instant)>>>,
+    }
+
+    impl Transport for SlowTransport {
+        fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+            self.sent.lock().unwrap().push((command.to_string(), Instant::now()));
+            thread::sleep(self.latency);
+            self.emulator.send_command(command)
+        }
+
+        fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError> {
+            self.emulator.receive_bytes(buf)
+        }
+
+        fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError> {
+            self.emulator.receive_payload()
+        }
+
+        fn receive_u64(&mut self) -> Result<u64, SwitchError> {
+            self.emulator.receive_u64()
+        }
+
+        fn receive_text(&mut self) -> Result<String, SwitchError> {
+            self.emulator.receive_text()
+        }
+
+        fn reconnect(&mut self) -> Result<(), SwitchError> {
+            self.emulator.reconnect()
+        }
+    }
+
+    #[test]
+    fn parse_all_step_kinds() {
+        let input_macro: InputMacro = "
+            # comment
+            A
+            B 100ms   # hold
+            wait 1.5s
+            press ZL
+            release zl
+            stick L 0 32767 500ms
+            stick right -32768 0
+            touch 640 360
+        "
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            input_macro.steps,
+            vec![
+                Step::Click(Button::A),
+                Step::Hold(Button::B, Duration::from_millis(100)),
+                Step::Wait(Duration::from_millis(1500)),
+                Step::Press(Button::ZL),
+                Step::Release(Button::ZL),
+                Step::Stick {
+                    stick: Stick::Left,
+                    x: 0,
+                    y: 32767,
+                    duration: Some(Duration::from_millis(500))
+                },
+                Step::Stick {
+                    stick: Stick::Right,
+                    x: -32768,
+                    y: 0,
+                    duration: None
+                },
+                Step::Touch(640, 360),
+            ]
+        );
+        assert_eq!(input_macro.duration(), Duration::from_millis(2100));
+    }
+
+    #[test]
+    fn parse_errors_report_line() {
+        let cases = [
+            ("A\nSTART", 2),
+            ("wait 2", 1),
+            ("wait -1s", 1),
+            ("A\n\nstick L 0 40000", 3),
+            ("touch 1280 0", 1),
+            ("stick M 0 0", 1),
+            ("press", 1),
+        ];
+        for (text, line) in cases {
+            let error = text.parse::<InputMacro>().unwrap_err();
+            assert_eq!(error.line, line, "{:?}: {}", text, error);
+        }
+    }
+
+    #[test]
+    fn load_errors_name_the_file_and_line() {
+        let path = std::env::temp_dir().join(format!("bad-macro-{}.txt", std::process::id()));
+        std::fs::write(&path, "A\nSTART\n").unwrap();
+        let error = InputMacro::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        match error {
+            SwitchError::InvalidArgument(message) => {
+                assert!(message.starts_with(&format!("{}: Line 2:", path.display())), "{}", message)
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn run_sends_commands_with_timing() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+        let input_macro: InputMacro = "A\nB 30ms\nwait 20ms\nstick L 0 100 10ms".parse().unwrap();
+
+        let start = Instant::now();
+        let outcome = input_macro.run(&mut switch, &AtomicBool::new(false)).unwrap();
+        assert_eq!(outcome, MacroOutcome::Completed);
+        assert!(start.elapsed() >= Duration::from_millis(60));
+
+        assert_eq!(
+            emulator.commands(),
+            vec![
+                "click A",
+                "press B",
+                "release B",
+                "setStick LEFT 0 100",
+                "setStick LEFT 0 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn holds_last_their_full_duration_despite_latency() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut switch = Switch::with_transport(SlowTransport {
+            emulator: Emulator::new(),
+            latency: Duration::from_millis(30),
+            sent: sent.clone(),
+        });
+        let input_macro: InputMacro = "A\nB 50ms\nstick L 0 100 50ms".parse().unwrap();
+        input_macro.run(&mut switch, &AtomicBool::new(false)).unwrap();
+
+        let sent = sent.lock().unwrap();
+        let sent_at = |command: &str| sent.iter().find(|(c, _)| c == command).unwrap().1;
+        let gaps = [
+            sent_at("release B") - sent_at("press B"),
+            sent_at("setStick LEFT 0 0") - sent_at("setStick LEFT 0 100"),
+        ];
+        for gap in gaps {
+            assert!(gap >= Duration::from_millis(80), "{:?}", gap);
+        }
+    }
+
+    #[test]
+    fn cancel_releases_held_inputs() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+        let input_macro: InputMacro = "press A\nstick R 100 0\nwait 10s\nB".parse().unwrap();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let start = Instant::now();
+        let outcome = input_macro.run(&mut switch, &cancel).unwrap();
+        canceller.join().unwrap();
+        assert_eq!(outcome, MacroOutcome::Cancelled);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let commands = emulator.commands();
+        assert_eq!(&commands[..2], ["press A", "setStick RIGHT 100 0"]);
+        assert!(commands[2..].contains(&"release A".to_string()));
+        assert!(commands[2..].contains(&"setStick RIGHT 0 0".to_string()));
+        assert!(!commands.contains(&"click B".to_string()));
+    }
+}
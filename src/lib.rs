@@ -0,0 +1,10 @@
+pub mod acnh_items;
+pub mod acnh_offsets;
+pub mod acnh_utils;
+pub mod acnh_worker;
+pub mod dmnt_cheats;
+pub mod input_macro;
+pub mod memory_scanner;
+pub mod memory_snapshot;
+pub mod memory_viewer;
+pub mod switch_utils;
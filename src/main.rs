@@ -1,14 +1,66 @@
-use std::error::Error;
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-mod switch_utils;
-mod acnh_utils;
-mod acnh_items;
+use switch_usb_hax::{
+    acnh_items, acnh_utils,
+    acnh_offsets::GameOffsets,
+    acnh_worker::{Request, Response, Worker},
+    dmnt_cheats::{self, CheatFile},
+    input_macro::InputMacro,
+    memory_scanner::{Filter, Scan, ValueType},
+    memory_snapshot::{self, Snapshot},
+    memory_viewer::{self, MemoryPage, ROW_LENGTH},
+    switch_utils::{
+        self, Address, Connection, ConnectionState, Screenshot, Switch, SwitchError, UsbDeviceInfo, UsbPort,
+        UsbWatcher, DEFAULT_PORT,
+    },
+};
 
-use eframe::{egui::{self, Button}, emath::Align, epaint::Color32};
+use eframe::{egui::{self, Button}, epaint::{Color32, TextureHandle}};
+
+/// How often the live screen preview is refreshed
+const PREVIEW_INTERVAL: Duration = Duration::from_secs(1);
+/// How often hotplug and connection state are checked while idle
+const CONNECTION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How many more times a write is tried when it reads back wrong
+const WRITE_RETRIES: u32 = 3;
+/// How often to check for results while the worker is busy
+const RESPONSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often the memory viewer rereads its page when auto refresh is on
+const MEMORY_REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+/// How often enabled cheats are applied again, to keep their values in place
+const CHEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How many bytes the memory viewer shows at once
+const MEMORY_PAGE_LENGTH: usize = 0x100;
+
+/// What the device picker has selected
+#[derive(Clone, PartialEq)]
+enum ConnectionChoice {
+    Usb(UsbDeviceInfo),
+    Network,
+    Emulator,
+}
 
 struct ACNHHax {
-    acnh_items: acnh_items::AcnhItems,
-    acnh: acnh_utils::ACNH,
+    acnh_items: Arc<acnh_items::AcnhItems>,
+    /// Does all the talking to the console, so the window never waits on it
+    worker: Worker,
+    /// Offsets of the connected game, once connected
+    offsets: Option<GameOffsets>,
+    state: Option<ConnectionState>,
+    devices: Vec<UsbDeviceInfo>,
+    /// Device to select in the picker once the worker has listed them
+    wanted_device: Option<Option<UsbPort>>,
+    usb_watcher: Option<UsbWatcher>,
+    connection_choice: ConnectionChoice,
+    network_address: String,
+    /// What we're connecting to, until the worker says how it went
+    connecting_to: Option<String>,
+    connected_to: Option<String>,
     inventory: Vec<acnh_utils::InventoryItem>,
     current_frame: u8,
     current_query: String,
@@ -16,21 +68,256 @@ struct ACNHHax {
     is_recipe: bool,
     bulk_items: bool,
     results: Vec<acnh_items::Item>,
+    last_error: Option<String>,
+    screenshot: Option<Screenshot>,
+    screen_texture: Option<TextureHandle>,
+    live_preview: bool,
+    last_capture: Option<Instant>,
+    show_memory_viewer: bool,
+    memory_page: MemoryPage,
+    memory_address: String,
+    memory_selection: Option<usize>,
+    memory_edit_type: ValueType,
+    memory_edit_value: String,
+    memory_auto_refresh: bool,
+    last_memory_refresh: Option<Instant>,
+    show_cheats: bool,
+    cheats: CheatFile,
+    last_cheat_run: Option<Instant>,
+    /// Where to record sysbot traffic to, if anywhere
+    recording: Option<PathBuf>,
+    /// Read every write back, for long bulk sessions where one might not take
+    verify_writes: bool,
 }
 
 impl ACNHHax {
+    fn refresh_devices(&mut self) {
+        self.worker.send(Request::ListDevices);
+    }
+
+    fn set_devices(&mut self, devices: Vec<UsbDeviceInfo>) {
+        if let Some(port) = self.wanted_device.take() {
+            if let Some(device) = devices.iter().find(|d| port.as_ref().is_none_or(|p| &d.port == p)) {
+                self.connection_choice = ConnectionChoice::Usb(device.clone());
+            }
+        }
+        self.devices = devices;
+    }
+
+    fn connect(&mut self) {
+        let connection = match &self.connection_choice {
+            ConnectionChoice::Usb(device) => Connection::Usb(Some(device.port.clone())),
+            ConnectionChoice::Network => match self.network_address.parse::<Connection>() {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.last_error = Some(e.to_string());
+                    return;
+                }
+            },
+            ConnectionChoice::Emulator => Connection::Emulator,
+        };
+        self.open(connection);
+    }
+
+    fn open(&mut self, connection: Connection) {
+        self.offsets = None;
+        self.state = None;
+        self.connected_to = None;
+        self.connecting_to = Some(describe_connection(&connection));
+        self.inventory.clear();
+        let write_retries = self.write_retries();
+        self.worker.send(Request::Connect { connection, recording: self.recording.clone(), write_retries });
+    }
+
+    fn write_retries(&self) -> Option<u32> {
+        self.verify_writes.then_some(WRITE_RETRIES)
+    }
+
+    fn set_connection(&mut self, offsets: GameOffsets, connection: &Connection) {
+        self.connecting_to = None;
+        self.connected_to = Some(format!("{} (ACNH {})", describe_connection(connection), offsets.version));
+        self.state = Some(ConnectionState::Connected);
+        self.last_error = None;
+        self.load_cheats(offsets.build_id);
+        // Start the memory viewer somewhere useful
+        if offsets.inventory.depth() == 0 {
+            self.memory_address = offsets.inventory.base.to_string();
+            self.memory_page.go_to(offsets.inventory.base, MEMORY_PAGE_LENGTH);
+            self.memory_selection = None;
+        }
+        self.offsets = Some(offsets);
+    }
+
+    /// Take in whatever the worker has finished since the last frame
+    fn handle_responses(&mut self, ctx: &egui::Context) {
+        let responses: Vec<Response> = self.worker.responses().collect();
+        for response in responses {
+            match response {
+                Response::Connected { connection, offsets } => self.set_connection(offsets, &connection),
+                Response::ConnectFailed(e) => {
+                    self.connecting_to = None;
+                    self.last_error = Some(e);
+                }
+                Response::State(state) => {
+                    if state == ConnectionState::Connected {
+                        self.last_error = None;
+                    }
+                    self.state = Some(state);
+                }
+                Response::Inventory(inventory) => {
+                    self.last_error = None;
+                    self.inventory = inventory;
+                }
+                Response::Devices(devices) => self.set_devices(devices),
+                Response::Screenshot(screenshot) => self.show_screenshot(ctx, screenshot),
+                Response::Memory(page) => {
+                    // Ignore pages the viewer has moved away from since asking
+                    if page.address() == self.memory_page.address() && page.length() == self.memory_page.length() {
+                        self.memory_page = page;
+                    }
+                }
+                Response::CheatsFailed(e) => {
+                    // Stop rather than retry a failing cheat every second
+                    for cheat in &mut self.cheats.cheats {
+                        cheat.enabled = false;
+                    }
+                    self.last_error = Some(e);
+                }
+                Response::Error(e) => self.last_error = Some(e),
+            }
+        }
+    }
+
+    /// Follow devices being plugged in and out. The worker keeps an eye on
+    /// the console's own connection.
+    fn poll_connection(&mut self) {
+        if self.usb_watcher.as_ref().is_some_and(|watcher| watcher.events().count() > 0) {
+            self.refresh_devices();
+        }
+    }
+
+    fn connection_state(&self) -> Option<ConnectionState> {
+        self.offsets.as_ref().and(self.state)
+    }
+
     fn update_inventory(&mut self) {
-        self.inventory = self.acnh.get_inventory(&self.acnh_items).unwrap();
+        if self.offsets.is_some() {
+            self.worker.send(Request::RefreshInventory);
+        }
+    }
+
+    fn update_screenshot(&mut self) {
+        self.last_capture = Some(Instant::now());
+        if self.offsets.is_some() {
+            self.worker.send(Request::Screenshot);
+        }
+    }
+
+    fn show_screenshot(&mut self, ctx: &egui::Context, screenshot: Screenshot) {
+        let image = egui::ColorImage::from_rgba_unmultiplied(
+            [screenshot.width as usize, screenshot.height as usize],
+            &screenshot.rgba,
+        );
+        match &mut self.screen_texture {
+            Some(texture) => texture.set(image, egui::TextureFilter::Linear),
+            None => self.screen_texture = Some(ctx.load_texture("screen", image, egui::TextureFilter::Linear)),
+        }
+        self.screenshot = Some(screenshot);
+    }
+
+    fn save_screenshot(&mut self) {
+        let Some(screenshot) = &self.screenshot else {
+            return;
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if let Err(e) = screenshot.save_png(format!("screenshot-{}.png", timestamp)) {
+            self.last_error = Some(format!("Could not save screenshot: {}", e));
+        }
+    }
+
+    /// Show the page at the typed-in address
+    fn go_to_memory(&mut self) {
+        match self.memory_address.parse::<Address>() {
+            Ok(address) => {
+                self.memory_page.go_to(address, MEMORY_PAGE_LENGTH);
+                self.memory_selection = None;
+                self.refresh_memory();
+            }
+            Err(e) => self.last_error = Some(e.to_string()),
+        }
+    }
+
+    fn turn_memory_page(&mut self, forward: bool) {
+        if forward {
+            let result = self.memory_page.next_page();
+            if self.report(result).is_none() {
+                return;
+            }
+        } else {
+            self.memory_page.previous_page();
+        }
+        self.memory_address = self.memory_page.address().to_string();
+        self.refresh_memory();
+    }
+
+    fn refresh_memory(&mut self) {
+        self.last_memory_refresh = Some(Instant::now());
+        if self.offsets.is_some() {
+            self.worker.send(Request::RefreshMemory(self.memory_page.clone()));
+        }
+    }
+
+    /// Write the edit value over the selected bytes
+    fn write_memory(&mut self) {
+        let Some(offset) = self.memory_selection else {
+            return;
+        };
+        let bytes = self.memory_edit_type.parse_value(&self.memory_edit_value);
+        if let Some(bytes) = self.report(bytes) {
+            self.worker.send(Request::WriteMemory { page: self.memory_page.clone(), offset, bytes });
+        }
+    }
+
+    /// Load the cheat file for `build_id`, if there is one
+    fn load_cheats(&mut self, build_id: u64) {
+        let path = dmnt_cheats::cheat_path(build_id);
+        self.cheats = CheatFile::default();
+        if path.exists() {
+            if let Some(cheats) = self.report(CheatFile::load(path)) {
+                self.cheats = cheats;
+            }
+        }
+    }
+
+    fn run_cheats(&mut self) {
+        self.last_cheat_run = Some(Instant::now());
+        if self.offsets.is_some() {
+            self.worker.send(Request::RunCheats(self.cheats.clone()));
+        }
+    }
+
+    /// Keep the latest switch error around for display instead of crashing
+    fn report<T>(&mut self, result: Result<T, SwitchError>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.last_error = None;
+                Some(value)
+            }
+            Err(e) => {
+                self.last_error = Some(e.to_string());
+                None
+            }
+        }
     }
 
     fn update_results(&mut self) {
         if !self.bulk_items {
             self.results = self.acnh_items.find_items(&self.current_query);
             if self.is_recipe {
-                self.results = self.results.iter().filter(|x| x.get_type() == acnh_items::ItemType::Recipe).map(|x| x.clone()).collect();
+                self.results = self.results.iter().filter(|x| x.get_type() == acnh_items::ItemType::Recipe).cloned().collect();
             }
             else {
-                self.results = self.results.iter().filter(|x| x.get_type() == acnh_items::ItemType::Item).map(|x| x.clone()).collect();
+                self.results = self.results.iter().filter(|x| x.get_type() == acnh_items::ItemType::Item).cloned().collect();
             }
         }
         else {
@@ -39,36 +326,319 @@ impl ACNHHax {
     }
 }
 
-impl Default for ACNHHax {
-    fn default() -> Self {
-        let acnh_items = acnh_items::AcnhItems::new();
-        let mut acnh = acnh_utils::ACNH::new(); 
-        let inventory = acnh.get_inventory(&acnh_items).unwrap();
-        Self { 
-            acnh_items, acnh,  inventory, current_frame: 0, current_query: String::new(),
-            current_amount: 1, is_recipe: false, bulk_items: false, results: Vec::new()
+impl ACNHHax {
+    fn new(connection: &Connection, recording: Option<PathBuf>) -> Self {
+        let acnh_items = Arc::new(acnh_items::AcnhItems::new());
+        let mut app = Self { 
+            worker: Worker::start(acnh_items.clone()), acnh_items, offsets: None, state: None, devices: Vec::new(),
+            wanted_device: None, usb_watcher: UsbWatcher::start().ok(),
+            connection_choice: ConnectionChoice::Emulator, network_address: format!("192.168.0.2:{}", DEFAULT_PORT),
+            connecting_to: None, connected_to: None, inventory: Vec::new(), current_frame: 0, current_query: String::new(),
+            current_amount: 1, is_recipe: false, bulk_items: false, results: Vec::new(), last_error: None,
+            screenshot: None, screen_texture: None, live_preview: false, last_capture: None,
+            show_memory_viewer: false, memory_page: MemoryPage::new(Address::Heap(0), MEMORY_PAGE_LENGTH),
+            memory_address: Address::Heap(0).to_string(), memory_selection: None, memory_edit_type: ValueType::U32,
+            memory_edit_value: String::new(), memory_auto_refresh: false, last_memory_refresh: None,
+            show_cheats: false, cheats: CheatFile::default(), last_cheat_run: None, recording, verify_writes: false,
+        };
+        app.refresh_devices();
+        app.connection_choice = match connection {
+            Connection::Usb(port) => {
+                app.wanted_device = Some(port.clone());
+                ConnectionChoice::Emulator
+            },
+            Connection::Network { host, port } => {
+                app.network_address = network_address(host, *port);
+                ConnectionChoice::Network
+            },
+            Connection::Emulator | Connection::Replay(_) => ConnectionChoice::Emulator,
+        };
+        app.open(connection.clone());
+        app
+    }
+
+    fn connection_panel(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Console");
+            let selected_text = match &self.connection_choice {
+                ConnectionChoice::Usb(device) => device.to_string(),
+                ConnectionChoice::Network => "Network".to_string(),
+                ConnectionChoice::Emulator => "Emulator".to_string(),
+            };
+            egui::ComboBox::from_id_source("device_picker").selected_text(selected_text).show_ui(ui, |ui| {
+                for device in &self.devices {
+                    ui.selectable_value(&mut self.connection_choice, ConnectionChoice::Usb(device.clone()), device.to_string());
+                }
+                ui.selectable_value(&mut self.connection_choice, ConnectionChoice::Network, "Network");
+                ui.selectable_value(&mut self.connection_choice, ConnectionChoice::Emulator, "Emulator");
+            });
+            if self.connection_choice == ConnectionChoice::Network {
+                ui.text_edit_singleline(&mut self.network_address);
+            }
+            if ui.button("Refresh").clicked() {
+                self.refresh_devices();
+            }
+            if ui.button("Connect").clicked() {
+                self.connect();
+            }
+            ui.checkbox(&mut self.show_memory_viewer, "Memory viewer");
+            ui.checkbox(&mut self.show_cheats, "Cheats");
+            if ui.checkbox(&mut self.verify_writes, "Verify writes").on_hover_text("Read every write back and retry it if it didn't take").changed() && self.offsets.is_some() {
+                self.worker.send(Request::SetVerifiedWrites(self.write_retries()));
+            }
+
+            if self.worker.is_busy() {
+                ui.spinner();
+                if ui.button("Cancel").clicked() {
+                    self.worker.cancel();
+                    self.connecting_to = None;
+                }
+            }
+
+            if let Some(description) = &self.connecting_to {
+                ui.label(format!("Connecting to {}...", description));
+                return;
+            }
+            match (&self.connected_to, self.connection_state()) {
+                (Some(description), Some(ConnectionState::Connected)) => {
+                    ui.label(format!("Connected to {}", description))
+                }
+                (Some(description), Some(ConnectionState::Reconnecting)) => {
+                    ui.colored_label(Color32::YELLOW, format!("Reconnecting to {}...", description))
+                }
+                (Some(description), _) => ui.colored_label(
+                    Color32::RED,
+                    format!("Lost connection to {}, waiting for it to come back", description),
+                ),
+                (None, _) => ui.colored_label(Color32::RED, "Not connected"),
+            };
+        });
+    }
+}
+
+impl ACNHHax {
+    fn memory_viewer(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Address");
+            if ui.text_edit_singleline(&mut self.memory_address).lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+                self.go_to_memory();
+            }
+            if ui.button("Go").clicked() {
+                self.go_to_memory();
+            }
+            if ui.button("< Page").clicked() {
+                self.turn_memory_page(false);
+            }
+            if ui.button("Page >").clicked() {
+                self.turn_memory_page(true);
+            }
+            if ui.button("Refresh").clicked() {
+                self.refresh_memory();
+            }
+            ui.checkbox(&mut self.memory_auto_refresh, "Auto refresh");
+        });
+
+        if self.memory_page.data().is_empty() {
+            ui.label("Nothing read yet");
+            return;
+        }
+
+        egui::Grid::new("memory_grid").spacing([4.0, 2.0]).show(ui, |ui| {
+            let base = self.memory_page.address();
+            for (row, bytes) in self.memory_page.data().chunks(ROW_LENGTH).enumerate() {
+                ui.monospace(format!("{:010X}", base.value() + (row * ROW_LENGTH) as u64));
+                for (column, byte) in bytes.iter().enumerate() {
+                    let offset = row * ROW_LENGTH + column;
+                    let mut text = egui::RichText::new(format!("{:02X}", byte)).monospace();
+                    // Bytes that changed since the last refresh stand out
+                    if self.memory_page.is_changed(offset) {
+                        text = text.color(Color32::RED);
+                    }
+                    if ui.selectable_label(self.memory_selection == Some(offset), text).clicked() {
+                        self.memory_selection = Some(offset);
+                    }
+                }
+                ui.monospace(memory_viewer::ascii(bytes));
+                ui.end_row();
+            }
+        });
+
+        let Some(offset) = self.memory_selection else {
+            ui.label("Click a byte to see it as a value and edit it");
+            return;
+        };
+        ui.separator();
+        if let Ok(address) = self.memory_page.address().offset(offset as u64) {
+            ui.label(format!("Selected {}", address));
+        }
+        egui::Grid::new("memory_values").show(ui, |ui| {
+            for (value_type, value) in self.memory_page.interpret(offset) {
+                ui.label(value_type.to_string());
+                ui.monospace(value);
+                ui.end_row();
+            }
+        });
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("memory_edit_type").selected_text(self.memory_edit_type.to_string()).show_ui(ui, |ui| {
+                for value_type in [ValueType::U8, ValueType::U16, ValueType::U32, ValueType::U64, ValueType::F32, ValueType::F64, ValueType::Bytes] {
+                    ui.selectable_value(&mut self.memory_edit_type, value_type, value_type.to_string());
+                }
+            });
+            ui.text_edit_singleline(&mut self.memory_edit_value);
+            if ui.button("Write").clicked() {
+                self.write_memory();
+            }
+        });
+    }
+}
+
+impl ACNHHax {
+    fn cheat_list(&mut self, ui: &mut egui::Ui) {
+        let Some(build_id) = self.offsets.as_ref().map(|offsets| offsets.build_id) else {
+            ui.label("Connect to a console to use cheats");
+            return;
+        };
+        let path = dmnt_cheats::cheat_path(build_id);
+        ui.horizontal(|ui| {
+            ui.label(path.display().to_string());
+            if ui.button("Reload").clicked() {
+                self.load_cheats(build_id);
+            }
+        });
+        if self.cheats.cheats.is_empty() {
+            ui.label("No cheats for this build");
+            return;
         }
+        if let Some(master) = &self.cheats.master {
+            ui.label(format!("Master cheat: {}", master.name));
+        }
+
+        let mut toggled = false;
+        egui::ScrollArea::vertical().id_source("cheat_scroll_area").show(ui, |ui| {
+            for cheat in &mut self.cheats.cheats {
+                let supported = cheat.is_supported();
+                let response = ui.add_enabled(supported, egui::Checkbox::new(&mut cheat.enabled, cheat.name.as_str()));
+                toggled |= match cheat.unsupported_reason() {
+                    Some(reason) => response.on_disabled_hover_text(reason),
+                    None => response,
+                }.changed();
+            }
+        });
+        if toggled {
+            self.run_cheats();
+        }
+    }
+}
+
+fn describe_connection(connection: &Connection) -> String {
+    match connection {
+        Connection::Usb(Some(port)) => format!("USB {}", port),
+        Connection::Usb(None) => "USB".to_string(),
+        Connection::Network { host, port } => network_address(host, *port),
+        Connection::Emulator => "emulator".to_string(),
+        Connection::Replay(path) => format!("replay of {}", path.display()),
+    }
+}
+
+/// `host:port`, with IPv6 hosts in brackets so the port can be told apart
+fn network_address(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Connect, recording the session to `recording` if given
+fn open_switch(connection: &Connection, recording: Option<&PathBuf>) -> Result<Switch, SwitchError> {
+    let switch = Switch::connect(connection)?;
+    match recording {
+        Some(path) => switch.record_to(path),
+        None => Ok(switch),
     }
 }
 
 impl eframe::App for ACNHHax {
-    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_connection();
+        self.handle_responses(ctx);
+        ctx.request_repaint_after(CONNECTION_POLL_INTERVAL);
+        if self.worker.is_busy() {
+            ctx.request_repaint_after(RESPONSE_POLL_INTERVAL);
+        }
+        let connected = self.connection_state() == Some(ConnectionState::Connected);
+
+        // Reads pile up behind a slow console otherwise
         self.current_frame = (self.current_frame + 1) % 5;
-        if self.current_frame == 0 {
+        if self.current_frame == 0 && connected && !self.worker.is_busy() {
             self.update_inventory();
         }
 
+        if self.live_preview && connected {
+            if self.last_capture.is_none_or(|t| t.elapsed() >= PREVIEW_INTERVAL) {
+                self.update_screenshot();
+            }
+            ctx.request_repaint_after(PREVIEW_INTERVAL);
+        }
+
+        if self.show_memory_viewer && self.memory_auto_refresh && connected {
+            if self.last_memory_refresh.is_none_or(|t| t.elapsed() >= MEMORY_REFRESH_INTERVAL) {
+                self.refresh_memory();
+            }
+            ctx.request_repaint_after(MEMORY_REFRESH_INTERVAL);
+        }
+
+        egui::TopBottomPanel::top("connection_panel").show(ctx, |ui| {
+            self.connection_panel(ui);
+        });
+
+        if connected && self.cheats.cheats.iter().any(|c| c.enabled) {
+            if self.last_cheat_run.is_none_or(|t| t.elapsed() >= CHEAT_INTERVAL) {
+                self.run_cheats();
+            }
+            ctx.request_repaint_after(CHEAT_INTERVAL);
+        }
+
+        let mut show_cheats = self.show_cheats;
+        egui::Window::new("Cheats").open(&mut show_cheats).show(ctx, |ui| {
+            self.cheat_list(ui);
+        });
+        self.show_cheats = show_cheats;
+
+        let mut show_memory_viewer = self.show_memory_viewer;
+        egui::Window::new("Memory viewer").open(&mut show_memory_viewer).show(ctx, |ui| {
+            self.memory_viewer(ui);
+        });
+        self.show_memory_viewer = show_memory_viewer;
+
+        egui::SidePanel::right("screen_panel").default_width(320.0).show(ctx, |ui| {
+            ui.heading("Screen");
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.live_preview, "Live preview");
+                if ui.button("Capture").clicked() {
+                    self.update_screenshot();
+                }
+                if ui.add_enabled(self.screenshot.is_some(), Button::new("Save PNG")).clicked() {
+                    self.save_screenshot();
+                }
+            });
+
+            if let Some(texture) = &self.screen_texture {
+                let size = texture.size_vec2();
+                ui.image(texture.id(), size * (ui.available_width() / size.x));
+            }
+        });
+
         egui::CentralPanel::default().show(ctx, |ui| {
             
             ui.horizontal(|ui| {
                 ui.label("Item to add");
-                let mut update_results = false;
-                if self.bulk_items {
-                    update_results = ui.text_edit_multiline(&mut self.current_query).changed();
+                let mut update_results = if self.bulk_items {
+                    ui.text_edit_multiline(&mut self.current_query).changed()
                 }
                 else {
-                    update_results = ui.text_edit_singleline(&mut self.current_query).changed();
-                }
+                    ui.text_edit_singleline(&mut self.current_query).changed()
+                };
 
                 
                 
@@ -82,7 +652,7 @@ impl eframe::App for ACNHHax {
                 }
             });
             
-            if self.results.len() > 0 {
+            if !self.results.is_empty() {
                 ui.label("Current results:");
                 egui::ScrollArea::vertical().max_height(32.0).id_source("results_scroll_area").show(ui, |ui| {
                     let mut i = 0;
@@ -101,25 +671,12 @@ impl eframe::App for ACNHHax {
             ui.horizontal(|ui| {
                 if ui.button("Fill inventory").clicked() {
                     if self.bulk_items {
-                        let queries: Vec<&str> = self.current_query.split("\n").collect();
-                        let mut slot = 0;
-                        for query in queries {
-                            let item = match self.is_recipe {
-                                true => self.acnh_items.find_recipe(query),
-                                false => self.acnh_items.find_item(query),
-                            };
-
-                            match item {
-                                Some(item) => {
-                                    self.acnh.set_inventory(slot, &item, self.current_amount).unwrap();
-                                },
-                                None => {},
-                            };
-                            slot += 1;
-                            if slot >= 40 {
-                                break;
-                            }
-                        }
+                        // Queries that match nothing leave their slot alone
+                        let items = self.current_query.split('\n').take(40).map(|query| match self.is_recipe {
+                            true => self.acnh_items.find_recipe(query),
+                            false => self.acnh_items.find_item(query),
+                        }).collect();
+                        self.worker.send(Request::SetInventorySlots { items, amount: self.current_amount });
                     }
                     else {
                         let item = match self.is_recipe {
@@ -128,20 +685,21 @@ impl eframe::App for ACNHHax {
                         };
     
     
-                        match item {
-                            Some(item) => {
-                                self.acnh.fill_inventory(&item, self.current_amount).unwrap();
-                            },
-                            None => {},
-                        };
+                        if let Some(item) = item {
+                            self.worker.send(Request::FillInventory { item, amount: self.current_amount });
+                        }
                     }
                 }
     
-                if ui.button("Clear inventory").clicked() {
-                    self.acnh.clear_inventory().unwrap();
+                if self.offsets.is_some() && ui.button("Clear inventory").clicked() {
+                    self.worker.send(Request::ClearInventory);
                 }
             });
 
+            if let Some(error) = &self.last_error {
+                ui.colored_label(Color32::RED, error);
+            }
+
             ui.separator();
             ui.spacing();
 
@@ -155,9 +713,11 @@ impl eframe::App for ACNHHax {
                 egui::Grid::new("inventory_grid").min_col_width(10.0).show(ui, |ui| {
                     for row in 0..4 {
                         for col in 0..10 {
-                            let inv_item = &self.inventory[row*10 + col];
+                            let Some(inv_item) = self.inventory.get(row*10 + col) else {
+                                continue;
+                            };
                             let fill_color: Color32 = match &inv_item.item {
-                                acnh_items::Item::Item { item_id, i_name, eng_name, color } => {
+                                acnh_items::Item::Item { item_id, .. } => {
                                     if *item_id == 0xfffe {
                                         Color32::BLACK
                                     }
@@ -165,7 +725,7 @@ impl eframe::App for ACNHHax {
                                         Color32::DARK_GREEN
                                     }
                                 },
-                                acnh_items::Item::Recipe { recipe_id, i_name, eng_name } => Color32::from_rgb(100, 100, 0),
+                                acnh_items::Item::Recipe { .. } => Color32::from_rgb(100, 100, 0),
                             };
 
                             let inv_cell = Button::new(format!("{:02}", row*10 + col)).fill(fill_color);
@@ -176,14 +736,10 @@ impl eframe::App for ACNHHax {
                                     false => self.acnh_items.find_item(&self.current_query),
                                 };
     
-                                match item {
-    
-                                    Some(item) => {
-                                        self.acnh.set_inventory((row*10 + col) as u32, &item, self.current_amount).unwrap();
-                                    },
-                                    None => {},
-                                    
-                                };
+                                if let Some(item) = item {
+                                    let slot = (row*10 + col) as u32;
+                                    self.worker.send(Request::SetInventory { slot, item, amount: self.current_amount });
+                                }
                             }
                         }
                         ui.end_row();
@@ -201,8 +757,187 @@ impl eframe::App for ACNHHax {
     }
 }
 
+/// How many scan results are printed after a scan or narrowing pass
+const PRINTED_SCAN_RESULTS: usize = 20;
+
+fn print_scan(scan: &Scan, file: &str) {
+    println!("{} candidates, saved to {}", scan.len(), file);
+    for (address, value) in scan.results().take(PRINTED_SCAN_RESULTS) {
+        println!("{}\t{}", address, scan.value_type().format_value(value));
+    }
+    if scan.len() > PRINTED_SCAN_RESULTS {
+        println!("... ({} more)", scan.len() - PRINTED_SCAN_RESULTS);
+    }
+}
+
+fn parse_filter(scan: &Scan, filter: &[&str]) -> Result<Filter, Box<dyn Error>> {
+    Ok(match filter {
+        ["exact", value] => Filter::Exact(scan.value_type().parse_value(value)?),
+        ["changed"] => Filter::Changed,
+        ["unchanged"] => Filter::Unchanged,
+        ["increased"] => Filter::Increased,
+        ["decreased"] => Filter::Decreased,
+        _ => return Err("Filter must be exact <value>, changed, unchanged, increased or decreased".into()),
+    })
+}
+
+/// Parse a length given in decimal or `0x` hex
+fn parse_length(text: &str) -> Result<usize, Box<dyn Error>> {
+    Ok(match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16)?,
+        None => text.parse()?,
+    })
+}
+
+fn print_diff(old_path: &str, new_path: &str) -> Result<(), Box<dyn Error>> {
+    let old = Snapshot::load(old_path)?;
+    let new = Snapshot::load(new_path)?;
+    for (path, snapshot) in [(old_path, &old), (new_path, &new)] {
+        println!(
+            "{}: title {:016X}, build {:016X}, {} bytes at {}, taken at {}",
+            path,
+            snapshot.title_id,
+            snapshot.build_id,
+            snapshot.data.len(),
+            snapshot.base,
+            snapshot.timestamp
+        );
+    }
+    if (old.title_id, old.build_id) != (new.title_id, new.build_id) {
+        println!("Warning: the snapshots come from different games or builds");
+    }
+
+    let changes = memory_snapshot::diff(&old, &new)?;
+    println!("{} changed ranges", changes.len());
+    for change in changes {
+        println!("{}", change);
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
+    // Usage: switch-usb-hax devices
+    //        switch-usb-hax [--record <log>] [connection] [macro <file>]
+    //        switch-usb-hax [connection] scan <type> <address> <length> <value> <file>
+    //        switch-usb-hax [connection] narrow <file> <filter> [value]
+    //        switch-usb-hax [connection] dump <address> <length> <file>
+    //        switch-usb-hax [connection] cheat <file> [name...]
+    //        switch-usb-hax diff <old file> <new file>
+    let usage = "Usage: switch-usb-hax devices
+       switch-usb-hax [--record <log>] [usb[:<bus>-<port>] | emulator | replay:<log> | host[:port]] [macro <file>]
+       switch-usb-hax <connection> scan <u8|u16|u32|u64|f32|f64|bytes> <address> <length> <value> <file>
+       switch-usb-hax <connection> narrow <file> <exact <value>|changed|unchanged|increased|decreased>
+       switch-usb-hax <connection> dump <address> <length> <file>
+       switch-usb-hax <connection> cheat <file> [name...]
+       switch-usb-hax diff <old file> <new file>";
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("devices") {
+        for device in switch_utils::list_devices()? {
+            println!("usb:{}\t{}", device.port, device.serial.as_deref().unwrap_or("-"));
+        }
+        return Ok(());
+    }
+    if args.peek().map(String::as_str) == Some("diff") {
+        return match (args.nth(1), args.next()) {
+            (Some(old), Some(new)) => print_diff(&old, &new),
+            _ => Err(usage.into()),
+        };
+    }
+
+    let recording = match args.peek().map(String::as_str) {
+        Some("--record") => Some(PathBuf::from(args.nth(1).ok_or(usage)?)),
+        _ => None,
+    };
+    let connection: Connection = match args.next() {
+        Some(arg) => arg.parse()?,
+        None => Connection::Usb(None),
+    };
+
+    let rest: Vec<String> = args.collect();
+    match rest.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+        [] => {}
+        ["macro", path] => {
+            let mut switch = open_switch(&connection, recording.as_ref())?;
+            let input_macro = InputMacro::load(path)?;
+            // Ctrl-C stops the macro, which lets go of anything it's holding
+            let cancel = Arc::new(AtomicBool::new(false));
+            let handler_cancel = cancel.clone();
+            ctrlc::set_handler(move || handler_cancel.store(true, Ordering::Relaxed))?;
+            let outcome = input_macro.run(&mut switch, &cancel)?;
+            println!("Macro {:?}", outcome);
+            return Ok(());
+        }
+        ["scan", value_type, address, length, value, file] => {
+            let value_type: ValueType = value_type.parse()?;
+            let length = parse_length(length)?;
+            let mut switch = open_switch(&connection, recording.as_ref())?;
+            let scan = Scan::first(
+                &mut switch,
+                value_type,
+                &value_type.parse_value(value)?,
+                address.parse()?,
+                length,
+                |done, total| eprint!("\rRead {}/{} bytes", done, total),
+            )?;
+            eprintln!();
+            if scan.is_empty() {
+                println!("No candidates found");
+                return Ok(());
+            }
+            scan.save(file)?;
+            print_scan(&scan, file);
+            return Ok(());
+        }
+        ["narrow", file, filter @ ..] => {
+            let mut scan = Scan::load(file)?;
+            let filter = parse_filter(&scan, filter)?;
+            let mut switch = open_switch(&connection, recording.as_ref())?;
+            scan.narrow(&mut switch, &filter)?;
+            if scan.is_empty() {
+                println!("No candidates left, so {} still holds the ones before this pass", file);
+                return Ok(());
+            }
+            scan.save(file)?;
+            print_scan(&scan, file);
+            return Ok(());
+        }
+        ["dump", address, length, file] => {
+            let mut switch = open_switch(&connection, recording.as_ref())?;
+            let snapshot = Snapshot::capture(&mut switch, address.parse()?, parse_length(length)?, |done, total| {
+                eprint!("\rRead {}/{} bytes", done, total)
+            })?;
+            eprintln!();
+            snapshot.save(file)?;
+            println!("Saved {} bytes from {} to {}", snapshot.data.len(), snapshot.base, file);
+            return Ok(());
+        }
+        ["cheat", path, names @ ..] => {
+            let mut cheats = CheatFile::load(path)?;
+            if names.is_empty() {
+                for cheat in &cheats.cheats {
+                    match cheat.unsupported_reason() {
+                        Some(reason) => println!("{} (can't run: {})", cheat.name, reason),
+                        None => println!("{}", cheat.name),
+                    }
+                }
+                return Ok(());
+            }
+            for name in names {
+                let cheat = cheats.cheats.iter_mut().find(|c| c.name == *name)
+                    .ok_or_else(|| format!("No cheat named {:?} in {}", name, path))?;
+                cheat.enabled = true;
+            }
+            let mut switch = open_switch(&connection, recording.as_ref())?;
+            cheats.run(&mut switch)?;
+            println!("Applied {} cheat(s)", names.len());
+            return Ok(());
+        }
+        _ => return Err(usage.into()),
+    }
+
+    // The GUI connects in the background, and opens even if that fails so
+    // another console can be picked
     let options = eframe::NativeOptions::default();
-    eframe::run_native("ACNH USB Hax", options, Box::new(|_| Box::new(ACNHHax::default())));
+    eframe::run_native("ACNH USB Hax", options, Box::new(move |_| Box::new(ACNHHax::new(&connection, recording))));
     Ok(())
 }
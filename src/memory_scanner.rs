@@ -0,0 +1,607 @@
+//! Cheat-Engine-style value scanning: find every address in a region holding
+//! some value, then narrow the candidates down by how they change.
+//!
+//! Scans save to a `;`-separated table that is readable as an export and can
+//! be loaded again to carry on narrowing later:
+//!
+//! ```text
+//! address ; type ; bytes ; value ;
+//! heap+0x00001000 ; u32 ; 0a000000 ; 10
+//! ```
+
+use std::{cmp::Ordering, fmt, mem, path::Path, str::FromStr};
+
+use crate::switch_utils::{self, hex, Address, Switch, SwitchError};
+
+/// Candidates further apart than this are read separately rather than as one
+/// range
+const MAX_READ_GAP: u64 = 0x1000;
+/// How much of a region a first scan holds in memory at once
+const SCAN_CHUNK_SIZE: usize = 0x40000;
+
+/// How scanned memory is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+    /// A byte pattern, as long as the value searched for
+    Bytes,
+}
+
+impl ValueType {
+    /// Size of one value, or `None` for byte patterns
+    pub fn size(self) -> Option<usize> {
+        match self {
+            ValueType::U8 => Some(1),
+            ValueType::U16 => Some(2),
+            ValueType::U32 | ValueType::F32 => Some(4),
+            ValueType::U64 | ValueType::F64 => Some(8),
+            ValueType::Bytes => None,
+        }
+    }
+
+    /// Values are only looked for at multiples of this
+    fn alignment(self) -> usize {
+        self.size().unwrap_or(1)
+    }
+
+    /// Parse a value as typed by a user: decimal or `0x` hex for integers,
+    /// hex bytes (spaces allowed) for patterns
+    pub fn parse_value(self, text: &str) -> Result<Vec<u8>, SwitchError> {
+        let invalid = || SwitchError::InvalidArgument(format!("Invalid {} value {:?}", self, text));
+        let text = text.trim();
+        let integer = || match text.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).map_err(|_| invalid()),
+            None => text.parse::<u64>().map_err(|_| invalid()),
+        };
+        Ok(match self {
+            ValueType::U8 => u8::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            ValueType::U16 => u16::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            ValueType::U32 => u32::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            ValueType::U64 => integer()?.to_le_bytes().to_vec(),
+            ValueType::F32 => text
+                .parse::<f32>()
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            ValueType::F64 => text
+                .parse::<f64>()
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            ValueType::Bytes => {
+                let digits: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+                let bytes = hex::decode(&digits).ok_or_else(invalid)?;
+                if bytes.is_empty() {
+                    return Err(invalid());
+                }
+                bytes
+            }
+        })
+    }
+
+    /// Format raw bytes holding one value of this type for display
+    pub fn format_value(self, bytes: &[u8]) -> String {
+        match self {
+            ValueType::F32 => f32::from_le_bytes(bytes.try_into().unwrap_or_default()).to_string(),
+            ValueType::F64 => f64::from_le_bytes(bytes.try_into().unwrap_or_default()).to_string(),
+            ValueType::Bytes => bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<String>>()
+                .join(" "),
+            _ => unsigned(bytes).to_string(),
+        }
+    }
+
+    /// Order two values of this type. Byte patterns are only ever equal or
+    /// not, and NaNs compare to nothing.
+    fn compare(self, a: &[u8], b: &[u8]) -> Option<Ordering> {
+        match self {
+            ValueType::F32 => f32::from_le_bytes(a.try_into().ok()?)
+                .partial_cmp(&f32::from_le_bytes(b.try_into().ok()?)),
+            ValueType::F64 => f64::from_le_bytes(a.try_into().ok()?)
+                .partial_cmp(&f64::from_le_bytes(b.try_into().ok()?)),
+            ValueType::Bytes => (a == b).then_some(Ordering::Equal),
+            _ => Some(unsigned(a).cmp(&unsigned(b))),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueType::U8 => "u8",
+            ValueType::U16 => "u16",
+            ValueType::U32 => "u32",
+            ValueType::U64 => "u64",
+            ValueType::F32 => "f32",
+            ValueType::F64 => "f64",
+            ValueType::Bytes => "bytes",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = SwitchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "u8" => Ok(ValueType::U8),
+            "u16" => Ok(ValueType::U16),
+            "u32" => Ok(ValueType::U32),
+            "u64" => Ok(ValueType::U64),
+            "f32" => Ok(ValueType::F32),
+            "f64" => Ok(ValueType::F64),
+            "bytes" => Ok(ValueType::Bytes),
+            _ => Err(SwitchError::InvalidArgument(format!(
+                "Unknown value type {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which candidates survive a narrowing pass, comparing each one's current
+/// value against the one it had at the previous scan
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// Equal to this value, as raw bytes
+    Exact(Vec<u8>),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Filter {
+    fn keeps(&self, value_type: ValueType, previous: &[u8], current: &[u8]) -> bool {
+        match self {
+            Filter::Exact(value) => value_type.compare(current, value) == Some(Ordering::Equal),
+            Filter::Changed => previous != current,
+            Filter::Unchanged => previous == current,
+            Filter::Increased => value_type.compare(current, previous) == Some(Ordering::Greater),
+            Filter::Decreased => value_type.compare(current, previous) == Some(Ordering::Less),
+        }
+    }
+}
+
+/// The candidates left after a scan, with the value each had when last read
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scan {
+    value_type: ValueType,
+    width: usize,
+    addresses: Vec<Address>,
+    /// `width` bytes per address
+    values: Vec<u8>,
+}
+
+impl Scan {
+    /// Read `length` bytes from `region` and keep every address holding
+    /// `value`, calling `progress(done, total)` as the region is read.
+    /// Numbers are only looked for at addresses aligned to their size, relative
+    /// to the start of the region. The region is read a piece at a time, so
+    /// large ones don't have to fit in memory.
+    pub fn first<F: FnMut(usize, usize)>(
+        switch: &mut Switch,
+        value_type: ValueType,
+        value: &[u8],
+        region: Address,
+        length: usize,
+        mut progress: F,
+    ) -> Result<Scan, SwitchError> {
+        let width = check_width(value_type, value)?;
+        let mut scan = Scan {
+            value_type,
+            width,
+            addresses: Vec::new(),
+            values: Vec::new(),
+        };
+
+        // The end of the previous piece is kept, for values that straddle two
+        let mut window: Vec<u8> = Vec::new();
+        let mut window_start: usize = 0;
+        let mut read = 0;
+        while read < length {
+            let mut chunk = vec![0; SCAN_CHUNK_SIZE.min(length - read)];
+            switch.read_bytes_chunked(region.offset(read as u64)?, &mut chunk, |done, _| {
+                progress(read + done, length)
+            })?;
+            read += chunk.len();
+            window.extend_from_slice(&chunk);
+
+            let first = window_start.next_multiple_of(value_type.alignment()) - window_start;
+            for start in (first..window.len().saturating_sub(width - 1)).step_by(value_type.alignment()) {
+                let current = &window[start..start + width];
+                if value_type.compare(current, value) == Some(Ordering::Equal) {
+                    scan.addresses.push(region.offset((window_start + start) as u64)?);
+                    scan.values.extend_from_slice(current);
+                }
+            }
+
+            let keep = window.len().min(width - 1);
+            window_start += window.len() - keep;
+            window.drain(..window.len() - keep);
+        }
+        Ok(scan)
+    }
+
+    /// Re-read every candidate and drop the ones `filter` rejects
+    pub fn narrow(&mut self, switch: &mut Switch, filter: &Filter) -> Result<(), SwitchError> {
+        if let Filter::Exact(value) = filter {
+            if value.len() != self.width {
+                return Err(SwitchError::InvalidArgument(format!(
+                    "Scan is for {}-byte values, got {} bytes",
+                    self.width,
+                    value.len()
+                )));
+            }
+        }
+
+        let current = self.read_current(switch)?;
+        let mut addresses = Vec::new();
+        let mut values = Vec::new();
+        for (i, address) in self.addresses.iter().enumerate() {
+            let range = i * self.width..(i + 1) * self.width;
+            if filter.keeps(
+                self.value_type,
+                &self.values[range.clone()],
+                &current[range.clone()],
+            ) {
+                addresses.push(*address);
+                values.extend_from_slice(&current[range]);
+            }
+        }
+        self.addresses = addresses;
+        self.values = values;
+        Ok(())
+    }
+
+    /// Current values of every candidate, reading nearby candidates together
+    /// and batching the reads
+    fn read_current(&self, switch: &mut Switch) -> Result<Vec<u8>, SwitchError> {
+        let width = self.width as u64;
+        // Candidate index ranges, and the memory each group covers
+        let mut groups = Vec::new();
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < self.addresses.len() {
+            let base = self.addresses[start];
+            let mut end = start + 1;
+            while let Some(next) = self.addresses.get(end) {
+                let previous_end = self.addresses[end - 1].value() + width;
+                if mem::discriminant(next) != mem::discriminant(&base)
+                    || next.value() < base.value()
+                    || next.value() > previous_end + MAX_READ_GAP
+                {
+                    break;
+                }
+                end += 1;
+            }
+
+            let span = (self.addresses[end - 1].value() - base.value() + width) as usize;
+            groups.push(start..end);
+            ranges.push((base, span));
+            start = end;
+        }
+
+        let mut current = vec![0; self.values.len()];
+        let memory = switch.read_batch(&ranges)?;
+        for ((group, (base, _)), memory) in groups.into_iter().zip(&ranges).zip(&memory) {
+            for i in group {
+                let offset = (self.addresses[i].value() - base.value()) as usize;
+                current[i * self.width..(i + 1) * self.width]
+                    .copy_from_slice(&memory[offset..offset + self.width]);
+            }
+        }
+        Ok(current)
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Every candidate with its value as of the last scan
+    pub fn results(&self) -> impl Iterator<Item = (Address, &[u8])> + '_ {
+        self.addresses
+            .iter()
+            .copied()
+            .zip(self.values.chunks(self.width))
+    }
+
+    /// Save the candidates to resume narrowing later. A scan with none left
+    /// can't be resumed, so it isn't saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SwitchError> {
+        if self.is_empty() {
+            return Err(SwitchError::InvalidArgument(
+                "Scan has no candidates left to save".into(),
+            ));
+        }
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scan, SwitchError> {
+        switch_utils::parse_file(path)
+    }
+}
+
+impl fmt::Display for Scan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "address ; type ; bytes ; value ;")?;
+        for (address, value) in self.results() {
+            writeln!(
+                f,
+                "{} ; {} ; {} ; {}",
+                address,
+                self.value_type,
+                hex::encode(value),
+                self.value_type.format_value(value)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Scan {
+    type Err = SwitchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut scan: Option<Scan> = None;
+        for (i, line) in s.lines().enumerate().skip(1) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |message: &str| {
+                SwitchError::InvalidArgument(format!("Line {}: {}", i + 1, message))
+            };
+            let cols: Vec<&str> = line.split(';').map(|c| c.trim()).collect();
+            let [address, value_type, bytes, ..] = cols.as_slice() else {
+                return Err(invalid("expected address ; type ; bytes"));
+            };
+            let address: Address = address.parse().map_err(|_| invalid("bad address"))?;
+            let value_type: ValueType = value_type.parse().map_err(|_| invalid("bad type"))?;
+            let value = hex::decode(bytes).ok_or_else(|| invalid("bad bytes"))?;
+
+            let scan = scan.get_or_insert_with(|| Scan {
+                value_type,
+                width: value.len(),
+                addresses: Vec::new(),
+                values: Vec::new(),
+            });
+            if value_type != scan.value_type || value.len() != scan.width || value.is_empty() {
+                return Err(invalid("all candidates must have the same type and size"));
+            }
+            scan.addresses.push(address);
+            scan.values.extend(value);
+        }
+        scan.ok_or_else(|| SwitchError::InvalidArgument("Scan has no candidates to resume".into()))
+    }
+}
+
+/// Make sure `value` is a valid value of `value_type`, returning its size
+fn check_width(value_type: ValueType, value: &[u8]) -> Result<usize, SwitchError> {
+    match value_type.size() {
+        Some(size) if size != value.len() => Err(SwitchError::InvalidArgument(format!(
+            "A {} is {} bytes, got {}",
+            value_type,
+            size,
+            value.len()
+        ))),
+        _ if value.is_empty() => Err(SwitchError::InvalidArgument("Empty scan value".into())),
+        _ => Ok(value.len()),
+    }
+}
+
+/// A little-endian unsigned integer of up to 8 bytes
+fn unsigned(bytes: &[u8]) -> u64 {
+    let mut padded = [0u8; 8];
+    padded[..bytes.len().min(8)].copy_from_slice(&bytes[..bytes.len().min(8)]);
+    u64::from_le_bytes(padded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::Emulator;
+
+    fn emulated() -> (Switch, Emulator) {
+        let emulator = Emulator::new();
+        (Switch::with_transport(emulator.clone()), emulator)
+    }
+
+    #[test]
+    fn scan_then_narrow() {
+        let (mut switch, emulator) = emulated();
+        for offset in [0x10, 0x24, 0x3000] {
+            emulator.write(Address::Heap(offset), &100u32.to_le_bytes());
+        }
+        // Unaligned, so not a u32 candidate
+        emulator.write(Address::Heap(0x41), &100u32.to_le_bytes());
+
+        let value = ValueType::U32.parse_value("100").unwrap();
+        let mut scan = Scan::first(
+            &mut switch,
+            ValueType::U32,
+            &value,
+            Address::Heap(0),
+            0x4000,
+            |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(
+            scan.results().map(|(a, _)| a).collect::<Vec<_>>(),
+            vec![
+                Address::Heap(0x10),
+                Address::Heap(0x24),
+                Address::Heap(0x3000)
+            ]
+        );
+
+        emulator.write(Address::Heap(0x24), &150u32.to_le_bytes());
+        emulator.write(Address::Heap(0x3000), &50u32.to_le_bytes());
+        let mut unchanged = scan.clone();
+        unchanged.narrow(&mut switch, &Filter::Unchanged).unwrap();
+        assert_eq!(unchanged.results().next().unwrap().0, Address::Heap(0x10));
+
+        scan.narrow(&mut switch, &Filter::Increased).unwrap();
+        assert_eq!(scan.len(), 1);
+        assert_eq!(
+            scan.results().next().unwrap(),
+            (Address::Heap(0x24), &150u32.to_le_bytes()[..])
+        );
+
+        scan.narrow(
+            &mut switch,
+            &Filter::Exact(ValueType::U32.parse_value("0x96").unwrap()),
+        )
+        .unwrap();
+        assert_eq!(scan.len(), 1);
+        scan.narrow(&mut switch, &Filter::Changed).unwrap();
+        assert!(scan.is_empty());
+    }
+
+    #[test]
+    fn floats_and_patterns() {
+        let (mut switch, emulator) = emulated();
+        emulator.write(Address::Main(0x20), &1.5f32.to_le_bytes());
+        emulator.write(Address::Main(0x33), &[0xde, 0xad, 0xbe, 0xef]);
+
+        let value = ValueType::F32.parse_value("1.5").unwrap();
+        let scan = Scan::first(
+            &mut switch,
+            ValueType::F32,
+            &value,
+            Address::Main(0),
+            0x100,
+            |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(scan.results().next().unwrap().0, Address::Main(0x20));
+        assert_eq!(
+            ValueType::F32.format_value(scan.results().next().unwrap().1),
+            "1.5"
+        );
+
+        let value = ValueType::Bytes.parse_value("DE AD be ef").unwrap();
+        let scan = Scan::first(
+            &mut switch,
+            ValueType::Bytes,
+            &value,
+            Address::Main(0),
+            0x100,
+            |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(scan.results().next().unwrap().0, Address::Main(0x33));
+    }
+
+    #[test]
+    fn saved_scans_resume() {
+        let (mut switch, emulator) = emulated();
+        emulator.write(Address::Heap(0x8), &[7]);
+        emulator.write(Address::Heap(0x2000), &[7]);
+
+        let scan = Scan::first(
+            &mut switch,
+            ValueType::U8,
+            &[7],
+            Address::Heap(0),
+            0x4000,
+            |_, _| {},
+        )
+        .unwrap();
+        let text = scan.to_string();
+        assert_eq!(
+            text,
+            "address ; type ; bytes ; value ;\nheap+0x00000008 ; u8 ; 07 ; 7\nheap+0x00002000 ; u8 ; 07 ; 7\n"
+        );
+
+        let mut resumed: Scan = text.parse().unwrap();
+        assert_eq!(resumed, scan);
+        emulator.write(Address::Heap(0x2000), &[6]);
+        resumed.narrow(&mut switch, &Filter::Decreased).unwrap();
+        assert_eq!(resumed.results().next().unwrap().0, Address::Heap(0x2000));
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        assert!(ValueType::U8.parse_value("256").is_err());
+        assert!(ValueType::U16.parse_value("-1").is_err());
+        assert!(ValueType::Bytes.parse_value("abc").is_err());
+        assert!(ValueType::Bytes.parse_value("").is_err());
+        assert!("i32".parse::<ValueType>().is_err());
+
+        let (mut switch, _) = emulated();
+        assert!(Scan::first(
+            &mut switch,
+            ValueType::U32,
+            &[1, 2],
+            Address::Heap(0),
+            0x10,
+            |_, _| {}
+        )
+        .is_err());
+        assert!("address ; type ; bytes ; value ;\n"
+            .parse::<Scan>()
+            .is_err());
+
+        let mut scan =
+            Scan::first(&mut switch, ValueType::U8, &[1], Address::Heap(0), 0x10, |_, _| {}).unwrap();
+        assert!(scan.is_empty());
+        let path = std::env::temp_dir().join("empty_scan_is_not_saved.txt");
+        assert!(scan.save(&path).is_err());
+        assert!(!path.exists());
+        scan.narrow(&mut switch, &Filter::Changed).unwrap();
+    }
+
+    #[test]
+    fn values_straddling_read_pieces_are_found() {
+        let (mut switch, emulator) = emulated();
+        let pattern = [0xde, 0xad, 0xbe, 0xef];
+        emulator.write(Address::Heap(SCAN_CHUNK_SIZE as u64 - 2), &pattern);
+        emulator.write(Address::Heap(SCAN_CHUNK_SIZE as u64 + 0x20), &pattern);
+
+        let mut progress = Vec::new();
+        let scan = Scan::first(
+            &mut switch,
+            ValueType::Bytes,
+            &pattern,
+            Address::Heap(0),
+            SCAN_CHUNK_SIZE + 0x100,
+            |done, total| progress.push((done, total)),
+        )
+        .unwrap();
+        let addresses: Vec<Address> = scan.results().map(|(address, _)| address).collect();
+        assert_eq!(
+            addresses,
+            vec![
+                Address::Heap(SCAN_CHUNK_SIZE as u64 - 2),
+                Address::Heap(SCAN_CHUNK_SIZE as u64 + 0x20)
+            ]
+        );
+        assert_eq!(progress.last(), Some(&(SCAN_CHUNK_SIZE + 0x100, SCAN_CHUNK_SIZE + 0x100)));
+    }
+}
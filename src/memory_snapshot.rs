@@ -0,0 +1,350 @@
+//! Memory snapshots, and diffs between them for working out which bytes an
+//! in-game action touches.
+//!
+//! A snapshot file is a fixed header followed by the raw memory:
+//!
+//! | bytes | field                                             |
+//! |-------|---------------------------------------------------|
+//! | 8     | magic, `SWSNAP01`                                 |
+//! | 8     | title ID                                          |
+//! | 8     | build ID                                          |
+//! | 1     | address space: 0 heap, 1 main, 2 absolute         |
+//! | 8     | base address                                      |
+//! | 8     | capture time, seconds since the Unix epoch        |
+//! | 8     | length of the memory that follows                 |
+//!
+//! All numbers are little-endian.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::memory_scanner::ValueType;
+use crate::switch_utils::{Address, Switch, SwitchError};
+
+const MAGIC: &[u8; 8] = b"SWSNAP01";
+
+/// Changed bytes at most this far apart are reported as one change, so a
+/// value with a few unchanged bytes in the middle isn't split up
+const MERGE_GAP: usize = 3;
+
+/// A copy of a range of memory and what was running when it was taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub title_id: u64,
+    pub build_id: u64,
+    pub base: Address,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Read `length` bytes from `base`, calling `progress(done, total)` as it goes
+    pub fn capture<F: FnMut(usize, usize)>(
+        switch: &mut Switch,
+        base: Address,
+        length: usize,
+        progress: F,
+    ) -> Result<Snapshot, SwitchError> {
+        let title_id = switch.title_id()?;
+        let build_id = switch.build_id()?;
+        let mut data = vec![0; length];
+        switch.read_bytes_chunked(base, &mut data, progress)?;
+        Ok(Snapshot {
+            title_id,
+            build_id,
+            base,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            data,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), SwitchError> {
+        let space = match self.base {
+            Address::Heap(_) => 0u8,
+            Address::Main(_) => 1,
+            Address::Absolute(_) => 2,
+        };
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.title_id.to_le_bytes())?;
+        writer.write_all(&self.build_id.to_le_bytes())?;
+        writer.write_all(&[space])?;
+        writer.write_all(&self.base.value().to_le_bytes())?;
+        writer.write_all(&self.timestamp.to_le_bytes())?;
+        writer.write_all(&(self.data.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.data)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Snapshot, SwitchError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SwitchError::InvalidArgument("Not a memory snapshot".into()));
+        }
+        let title_id = read_u64(&mut reader)?;
+        let build_id = read_u64(&mut reader)?;
+        let mut space = [0u8; 1];
+        reader.read_exact(&mut space)?;
+        let base = match space[0] {
+            0 => Address::Heap(read_u64(&mut reader)?),
+            1 => Address::Main(read_u64(&mut reader)?),
+            2 => Address::Absolute(read_u64(&mut reader)?),
+            other => {
+                return Err(SwitchError::InvalidArgument(format!(
+                    "Unknown address space {} in snapshot",
+                    other
+                )))
+            }
+        };
+        let timestamp = read_u64(&mut reader)?;
+        let length = read_u64(&mut reader)?;
+
+        let mut data = Vec::new();
+        reader.take(length).read_to_end(&mut data)?;
+        if data.len() as u64 != length {
+            return Err(SwitchError::ShortRead {
+                expected: length as usize,
+                received: data.len(),
+            });
+        }
+        Ok(Snapshot {
+            title_id,
+            build_id,
+            base,
+            timestamp,
+            data,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SwitchError> {
+        self.write_to(std::io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Snapshot, SwitchError> {
+        Snapshot::read_from(std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, SwitchError> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// A run of bytes that differs between two snapshots
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub address: Address,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+    /// The change read as each value type whose naturally aligned slot
+    /// contains all of it
+    pub interpretations: Vec<Interpretation>,
+}
+
+/// One way of reading a changed value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interpretation {
+    pub value_type: ValueType,
+    pub address: Address,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |bytes: &[u8]| ValueType::Bytes.format_value(bytes);
+        write!(
+            f,
+            "{} ({} bytes): {} -> {}",
+            self.address,
+            self.old.len(),
+            hex(&self.old),
+            hex(&self.new)
+        )?;
+        for interpretation in &self.interpretations {
+            write!(
+                f,
+                "\n    {} at {}: {} -> {}",
+                interpretation.value_type,
+                interpretation.address,
+                interpretation.old,
+                interpretation.new
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Every changed range in the memory both snapshots cover
+pub fn diff(old: &Snapshot, new: &Snapshot) -> Result<Vec<Change>, SwitchError> {
+    if std::mem::discriminant(&old.base) != std::mem::discriminant(&new.base) {
+        return Err(SwitchError::InvalidArgument(format!(
+            "Snapshots at {} and {} are in different address spaces",
+            old.base, new.base
+        )));
+    }
+
+    // Work in offsets from the start of the overlap
+    let start = old.base.value().max(new.base.value());
+    let end = (old.base.value().saturating_add(old.data.len() as u64))
+        .min(new.base.value().saturating_add(new.data.len() as u64));
+    if start >= end {
+        return Ok(Vec::new());
+    }
+    let old_bytes =
+        &old.data[(start - old.base.value()) as usize..(end - old.base.value()) as usize];
+    let new_bytes =
+        &new.data[(start - new.base.value()) as usize..(end - new.base.value()) as usize];
+    let base = old.base.with_value(start);
+
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for i in (0..old_bytes.len()).filter(|&i| old_bytes[i] != new_bytes[i]) {
+        match ranges.last_mut() {
+            Some((_, end)) if i - *end <= MERGE_GAP => *end = i + 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(from, to)| {
+            Ok(Change {
+                address: base.offset(from as u64)?,
+                old: old_bytes[from..to].to_vec(),
+                new: new_bytes[from..to].to_vec(),
+                interpretations: interpret(base, old_bytes, new_bytes, from, to)?,
+            })
+        })
+        .collect()
+}
+
+fn interpret(
+    base: Address,
+    old: &[u8],
+    new: &[u8],
+    from: usize,
+    to: usize,
+) -> Result<Vec<Interpretation>, SwitchError> {
+    let types = [
+        ValueType::U8,
+        ValueType::U16,
+        ValueType::U32,
+        ValueType::U64,
+        ValueType::F32,
+        ValueType::F64,
+    ];
+    let mut interpretations = Vec::new();
+    for value_type in types {
+        let size = value_type.size().unwrap_or(1);
+        // Alignment is relative to the address, not to where the snapshot starts
+        let slot =
+            ((base.value() as usize + from) / size * size).wrapping_sub(base.value() as usize);
+        if slot > from || to > slot + size || slot + size > old.len() {
+            continue;
+        }
+        interpretations.push(Interpretation {
+            value_type,
+            address: base.offset(slot as u64)?,
+            old: value_type.format_value(&old[slot..slot + size]),
+            new: value_type.format_value(&new[slot..slot + size]),
+        });
+    }
+    Ok(interpretations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, EMULATOR_TITLE_ID};
+
+    #[test]
+    fn capture_and_round_trip() {
+        let emulator = Emulator::new();
+        emulator.write(Address::Heap(0x1000), &[1, 2, 3, 4]);
+        let mut switch = Switch::with_transport(emulator);
+
+        let snapshot =
+            Snapshot::capture(&mut switch, Address::Heap(0x1000), 0x10, |_, _| {}).unwrap();
+        assert_eq!(snapshot.title_id, EMULATOR_TITLE_ID);
+        assert_eq!(&snapshot.data[..4], &[1, 2, 3, 4]);
+
+        let mut file = Vec::new();
+        snapshot.write_to(&mut file).unwrap();
+        assert_eq!(file.len(), 49 + 0x10);
+        assert_eq!(Snapshot::read_from(file.as_slice()).unwrap(), snapshot);
+
+        assert!(Snapshot::read_from(&file[..60]).is_err());
+        file[0] = b'X';
+        assert!(Snapshot::read_from(file.as_slice()).is_err());
+    }
+
+    #[test]
+    fn diff_reports_typed_changes() {
+        let snapshot = |base: u64, data: Vec<u8>| Snapshot {
+            title_id: 0,
+            build_id: 0,
+            base: Address::Heap(base),
+            timestamp: 0,
+            data,
+        };
+        let old = snapshot(0x100, vec![0; 0x20]);
+        let mut data = vec![0; 0x20];
+        data[0x4] = 0x0a; // one byte of a u32
+        data[0x11] = 0x01; // straddles the two halves of a u16 pair...
+        data[0x12] = 0x02; // ...so only u32 and wider cover it
+        let new = snapshot(0x100, data);
+
+        let changes = diff(&old, &new).unwrap();
+        assert_eq!(changes.len(), 2);
+
+        assert_eq!(changes[0].address, Address::Heap(0x104));
+        let types: Vec<ValueType> = changes[0]
+            .interpretations
+            .iter()
+            .map(|i| i.value_type)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ValueType::U8,
+                ValueType::U16,
+                ValueType::U32,
+                ValueType::U64,
+                ValueType::F32,
+                ValueType::F64
+            ]
+        );
+        assert_eq!(changes[0].interpretations[2].new, "10");
+
+        assert_eq!(changes[1].address, Address::Heap(0x111));
+        assert_eq!(changes[1].new, vec![1, 2]);
+        let u32_reading = &changes[1].interpretations[0];
+        assert_eq!(u32_reading.value_type, ValueType::U32);
+        assert_eq!(u32_reading.address, Address::Heap(0x110));
+        assert_eq!(u32_reading.new, (0x0002_0100u32).to_string());
+
+        // Only the overlap is compared
+        let shifted = snapshot(0x110, new.data[0x10..].to_vec());
+        assert_eq!(
+            diff(&old, &shifted).unwrap()[0].address,
+            Address::Heap(0x111)
+        );
+        assert!(diff(
+            &old,
+            &Snapshot {
+                base: Address::Main(0x100),
+                ..old.clone()
+            }
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! A page of live memory for the hex viewer: what it held at the last refresh,
+//! which bytes changed since the one before, and edits written back in place.
+
+use crate::memory_scanner::ValueType;
+use crate::switch_utils::{Address, Switch, SwitchError};
+
+/// Bytes shown on each row of the viewer
+pub const ROW_LENGTH: usize = 16;
+
+/// The value types a selection is shown as
+const INTERPRETED_TYPES: [ValueType; 6] = [
+    ValueType::U8,
+    ValueType::U16,
+    ValueType::U32,
+    ValueType::U64,
+    ValueType::F32,
+    ValueType::F64,
+];
+
+/// One page of memory and how it has changed between refreshes
+#[derive(Debug, Clone)]
+pub struct MemoryPage {
+    address: Address,
+    length: usize,
+    data: Vec<u8>,
+    changed: Vec<bool>,
+}
+
+impl MemoryPage {
+    /// A page of `length` bytes at `address`, empty until the first refresh
+    pub fn new(address: Address, length: usize) -> Self {
+        MemoryPage {
+            address,
+            length: length.max(1),
+            data: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// How many bytes the page covers
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// The bytes read at the last refresh
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whether the byte at `offset` differs from the refresh before last
+    pub fn is_changed(&self, offset: usize) -> bool {
+        self.changed.get(offset).copied().unwrap_or(false)
+    }
+
+    /// Show `length` bytes at `address` instead. The page is empty until the
+    /// next refresh.
+    pub fn go_to(&mut self, address: Address, length: usize) {
+        self.address = address;
+        self.length = length.max(1);
+        self.data.clear();
+        self.changed.clear();
+    }
+
+    /// Move on by one page
+    pub fn next_page(&mut self) -> Result<(), SwitchError> {
+        let address = self.address.offset(self.length as u64)?;
+        self.go_to(address, self.length);
+        Ok(())
+    }
+
+    /// Move back by one page, stopping at the start of the address space
+    pub fn previous_page(&mut self) {
+        let address = self
+            .address
+            .with_value(self.address.value().saturating_sub(self.length as u64));
+        self.go_to(address, self.length);
+    }
+
+    /// Read the page again, marking the bytes that differ from the last read
+    pub fn refresh(&mut self, switch: &mut Switch) -> Result<(), SwitchError> {
+        let mut data = vec![0; self.length];
+        switch.read_bytes_chunked(self.address, &mut data, |_, _| {})?;
+        self.changed = if self.data.len() == data.len() {
+            self.data.iter().zip(&data).map(|(a, b)| a != b).collect()
+        } else {
+            vec![false; data.len()]
+        };
+        self.data = data;
+        Ok(())
+    }
+
+    /// Write `bytes` at `offset` into the page, then read the page back so it
+    /// shows what the console actually holds
+    pub fn write(
+        &mut self,
+        switch: &mut Switch,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), SwitchError> {
+        if offset + bytes.len() > self.length {
+            return Err(SwitchError::InvalidArgument(format!(
+                "{} bytes at offset 0x{:X} don't fit in the page",
+                bytes.len(),
+                offset
+            )));
+        }
+        switch.write_bytes(self.address.offset(offset as u64)?, bytes)?;
+        self.refresh(switch)
+    }
+
+    /// The bytes from `offset` read as each numeric type that fits in the page
+    pub fn interpret(&self, offset: usize) -> Vec<(ValueType, String)> {
+        INTERPRETED_TYPES
+            .iter()
+            .filter_map(|&value_type| {
+                let size = value_type.size()?;
+                let bytes = self.data.get(offset..offset + size)?;
+                Some((value_type, value_type.format_value(bytes)))
+            })
+            .collect()
+    }
+}
+
+/// Printable ASCII as itself, everything else as `.`
+pub fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::Emulator;
+
+    #[test]
+    fn refresh_marks_changes_and_writes_go_through() {
+        let emulator = Emulator::new();
+        emulator.write(Address::Heap(0x1000), b"Hi!\x00\x2a");
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        let mut page = MemoryPage::new(Address::Heap(0x1000), 0x20);
+        page.refresh(&mut switch).unwrap();
+        assert_eq!(ascii(&page.data()[..5]), "Hi!.*");
+        assert!(!(0..page.length()).any(|i| page.is_changed(i)));
+
+        emulator.write(Address::Heap(0x1004), &[0x2b]);
+        page.refresh(&mut switch).unwrap();
+        let changed: Vec<usize> = (0..page.length()).filter(|&i| page.is_changed(i)).collect();
+        assert_eq!(changed, vec![4]);
+
+        page.write(&mut switch, 8, &1.5f32.to_le_bytes()).unwrap();
+        assert_eq!(
+            emulator.read(Address::Heap(0x1008), 4),
+            1.5f32.to_le_bytes()
+        );
+        let readings = page.interpret(8);
+        assert_eq!(readings[2], (ValueType::U32, 0x3FC0_0000u32.to_string()));
+        assert_eq!(readings[4], (ValueType::F32, "1.5".to_string()));
+
+        // Near the end of the page only the narrower types fit
+        assert_eq!(page.interpret(0x1e).len(), 2);
+        assert!(page.write(&mut switch, 0x1e, &[0; 4]).is_err());
+    }
+
+    #[test]
+    fn paging_moves_by_the_page_length() {
+        let mut page = MemoryPage::new(Address::Main(0x80), 0x100);
+        page.previous_page();
+        assert_eq!(page.address(), Address::Main(0));
+        page.next_page().unwrap();
+        assert_eq!(page.address(), Address::Main(0x100));
+
+        page.go_to(Address::Absolute(u64::MAX - 0x10), 0x100);
+        assert!(page.next_page().is_err());
+    }
+}
@@ -1,164 +1,931 @@
-use rusb::{self, Context, Device, DeviceDescriptor, DeviceHandle, Direction, UsbContext};
-use std::{error::Error, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+mod address;
+mod batch;
+mod emulator;
+mod error;
+pub(crate) mod hex;
+mod hotplug;
+mod input;
+mod pointer;
+mod recording;
+mod screenshot;
+mod tcp;
+mod usb;
+
+pub use address::Address;
+pub use emulator::{
+    Emulator, EMULATOR_BUILD_ID, EMULATOR_HEAP_BASE, EMULATOR_MAIN_BASE, EMULATOR_TITLE_ID,
+};
+pub use error::SwitchError;
+pub use hotplug::{DeviceEvent, UsbWatcher};
+pub use input::{Button, Stick, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use pointer::PointerChain;
+pub use recording::{Recorder, Replay};
+pub use screenshot::Screenshot;
+pub use tcp::{TcpTransport, DEFAULT_PORT};
+pub use usb::{list_devices, UsbDeviceInfo, UsbPort, UsbTransport};
+
+/// Read and parse the file at `path`, naming the file in any
+/// [`SwitchError::InvalidArgument`] the parse turns up
+pub(crate) fn parse_file<T, P>(path: P) -> Result<T, SwitchError>
+where
+    T: FromStr,
+    T::Err: Into<SwitchError>,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    std::fs::read_to_string(path)?
+        .parse()
+        .map_err(|e: T::Err| match e.into() {
+            SwitchError::InvalidArgument(message) => {
+                SwitchError::InvalidArgument(format!("{}: {}", path.display(), message))
+            }
+            e => e,
+        })
+}
+
+/// How to reach the sysbot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Connection {
+    /// usb-botbase over the device on the given port, or the first one found
+    Usb(Option<UsbPort>),
+    /// sys-botbase over the network
+    Network { host: String, port: u16 },
+    /// In-process emulated sysbot, for offline development
+    Emulator,
+    /// Answers from a session recorded with [`Switch::record_to`]
+    Replay(PathBuf),
+}
+
+impl FromStr for Connection {
+    type Err = SwitchError;
+
+    /// Parse `usb`, `usb:<bus>-<port>[.<port>...]`, `emulator`,
+    /// `replay:<file>`, `host` or `host:port`. IPv6 addresses take a port as
+    /// `[address]:port`; bare ones use the default port.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("usb") {
+            return Ok(Connection::Usb(None));
+        }
+        if let Some(port) = s.strip_prefix("usb:") {
+            return Ok(Connection::Usb(Some(port.parse()?)));
+        }
+        if s.eq_ignore_ascii_case("emulator") {
+            return Ok(Connection::Emulator);
+        }
+        if let Some(path) = s.strip_prefix("replay:") {
+            return Ok(Connection::Replay(PathBuf::from(path)));
+        }
+
+        let invalid = || SwitchError::InvalidArgument(format!("Invalid address {:?}", s));
+        let parse_port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, DEFAULT_PORT),
+                _ => (host, parse_port(rest.strip_prefix(':').ok_or_else(invalid)?)?),
+            }
+        } else if s.matches(':').count() > 1 {
+            (s, DEFAULT_PORT)
+        } else {
+            match s.rsplit_once(':') {
+                Some((host, port)) => (host, parse_port(port)?),
+                None => (s, DEFAULT_PORT),
+            }
+        };
+        if host.is_empty() {
+            return Err(SwitchError::InvalidArgument(format!("Missing host in {:?}", s)));
+        }
+
+        Ok(Connection::Network {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Whether a `Switch` can currently reach its sysbot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// The link dropped; the next command tries to reconnect
+    Disconnected,
+    /// A reconnect is in progress
+    Reconnecting,
+}
+
+/// A connection to a sysbot that can carry commands and their replies.
+///
+/// Framing (length prefixes, line endings, hex encoding, ...) is the transport's
+/// business; `Switch` only deals in commands and payloads.
+pub trait Transport: Send {
+    /// Send a single sysbot command, e.g. `peek 0x12345678 0x4`
+    fn send_command(&mut self, command: &str) -> Result<(), SwitchError>;
+
+    /// Receive a reply payload of exactly `buf.len()` bytes into `buf`
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError>;
+
+    /// Receive a reply payload whose size is only known once it arrives
+    fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError>;
+
+    /// Receive a single 64-bit number, such as a resolved pointer
+    fn receive_u64(&mut self) -> Result<u64, SwitchError>;
+
+    /// Receive a line of text, such as the sysbot version
+    fn receive_text(&mut self) -> Result<String, SwitchError>;
+
+    /// Re-establish the connection after it dropped
+    fn reconnect(&mut self) -> Result<(), SwitchError>;
+
+    /// Whether the sysbot is known to be reachable, without sending anything.
+    /// `None` if the transport has no way to tell.
+    fn is_present(&mut self) -> Option<bool> {
+        None
+    }
+}
+
+/// Largest single `peek` issued by the chunked read helpers
+pub const DEFAULT_READ_CHUNK_SIZE: usize = 0x4000;
+/// Largest single `poke` issued by the chunked write helpers; sysbot's command
+/// line buffer has to fit the data as hex text
+pub const DEFAULT_WRITE_CHUNK_SIZE: usize = 0x800;
+/// Most ranges put into a single `peekMulti`
+const MAX_MULTI_PEEK_RANGES: usize = 32;
 
 pub struct Switch {
-    read_endpoint: Endpoint,
-    write_endpoint: Endpoint,
-    switch_handle: DeviceHandle<Context>,
+    transport: Box<dyn Transport>,
+    server_pointers: bool,
+    read_chunk_size: usize,
+    write_chunk_size: usize,
+    multi_peek: bool,
+    /// Extra attempts at a write that reads back wrong, or `None` to trust pokes
+    write_retries: Option<u32>,
+    state: ConnectionState,
+    listeners: Vec<Sender<ConnectionState>>,
+    /// Resent if the link drops while waiting for its reply
+    last_command: String,
 }
 
 impl Switch {
     /// Create a new connection to the Switch sysbot over USB
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let ctx = rusb::Context::new()?;
-
-        let switch_device = ctx
-            .open_device_with_vid_pid(0x057E, 0x3000)
-            .expect("Couldn't find switch device")
-            .device();
-        let switch_descriptor = switch_device
-            .device_descriptor()
-            .expect("Could not get switch device descriptor");
-        let switch_handle = switch_device.open().unwrap();
-
-        let read_endpoint = get_switch_enpoint(&switch_descriptor, &switch_device, Direction::In)
-            .expect("Could not get read endpoint");
-        let write_endpoint = get_switch_enpoint(&switch_descriptor, &switch_device, Direction::Out)
-            .expect("Could not get write endoint");
-
-        Ok(Switch {
-            read_endpoint,
-            write_endpoint,
-            switch_handle,
-        })
+    pub fn new() -> Result<Self, SwitchError> {
+        Ok(Switch::with_transport(UsbTransport::new()?))
+    }
+
+    /// Connect to the Switch sysbot using `connection`
+    pub fn connect(connection: &Connection) -> Result<Self, SwitchError> {
+        match connection {
+            Connection::Usb(port) => Ok(Switch::with_transport(UsbTransport::open(port.as_ref())?)),
+            Connection::Network { host, port } => {
+                Ok(Switch::with_transport(TcpTransport::connect(host, *port)?))
+            }
+            Connection::Emulator => Ok(Switch::with_transport(Emulator::new())),
+            Connection::Replay(path) => Ok(Switch::with_transport(Replay::load(path)?)),
+        }
+    }
+
+    /// Log every command sent and every reply received from now on to the
+    /// file at `path`, which can later be replayed with [`Connection::Replay`]
+    pub fn record_to<P: AsRef<Path>>(mut self, path: P) -> Result<Self, SwitchError> {
+        let log = std::fs::File::create(path)?;
+        self.transport = Box::new(Recorder::new(self.transport, Box::new(log)));
+        Ok(self)
+    }
+
+    /// Create a Switch that talks to the sysbot through `transport`
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Self {
+        Switch {
+            transport: Box::new(transport),
+            server_pointers: true,
+            read_chunk_size: DEFAULT_READ_CHUNK_SIZE,
+            write_chunk_size: DEFAULT_WRITE_CHUNK_SIZE,
+            multi_peek: true,
+            write_retries: None,
+            state: ConnectionState::Connected,
+            listeners: Vec::new(),
+            last_command: String::new(),
+        }
+    }
+
+    /// Whether the sysbot is currently reachable
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Get notified of every connection state change from now on
+    pub fn subscribe(&mut self) -> Receiver<ConnectionState> {
+        let (sender, receiver) = mpsc::channel();
+        self.listeners.push(sender);
+        receiver
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            self.state = state;
+            self.listeners.retain(|listener| listener.send(state).is_ok());
+        }
     }
 
-    fn send_command(&mut self, command: String) -> Result<(), Box<dyn Error>> {
-        send_command(&mut self.switch_handle, &self.write_endpoint, command)
+    /// Catch up with hotplug notifications: mark the connection lost if the
+    /// device was unplugged, and reconnect if it came back. Cheap enough to
+    /// call every frame.
+    pub fn poll_connection(&mut self) -> ConnectionState {
+        match (self.state, self.transport.is_present()) {
+            (ConnectionState::Connected, Some(false)) => {
+                self.set_state(ConnectionState::Disconnected)
+            }
+            (ConnectionState::Disconnected, Some(true)) => {
+                let _ = self.reconnect();
+            }
+            _ => {}
+        }
+        self.state
+    }
+
+    /// Drop the current connection and open it again, re-resolving endpoints
+    pub fn reconnect(&mut self) -> Result<(), SwitchError> {
+        self.set_state(ConnectionState::Reconnecting);
+        let result = self.transport.reconnect();
+        self.set_state(match result {
+            Ok(()) => ConnectionState::Connected,
+            Err(_) => ConnectionState::Disconnected,
+        });
+        result
+    }
+
+    /// Reconnect after `error` if it means the link dropped, otherwise (or if
+    /// reconnecting fails) hand `error` back
+    fn recover(&mut self, error: SwitchError) -> Result<(), SwitchError> {
+        if !error.is_disconnect() {
+            return Err(error);
+        }
+        self.set_state(ConnectionState::Disconnected);
+        self.reconnect().map_err(|_| error)
+    }
+
+    fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+        if self.state != ConnectionState::Connected {
+            if self.transport.is_present() == Some(false) {
+                return Err(SwitchError::Disconnected);
+            }
+            self.reconnect()?;
+        }
+        self.last_command = command.to_string();
+        match self.transport.send_command(command) {
+            Err(e) => {
+                self.recover(e)?;
+                self.transport.send_command(command)
+            }
+            ok => ok,
+        }
+    }
+
+    /// Receive the reply to the last command, resending the command once if
+    /// the link dropped before the reply arrived
+    fn receive<T>(
+        &mut self,
+        mut receive: impl FnMut(&mut dyn Transport) -> Result<T, SwitchError>,
+    ) -> Result<T, SwitchError> {
+        match receive(self.transport.as_mut()) {
+            Err(e) => {
+                self.recover(e)?;
+                let command = self.last_command.clone();
+                self.transport.send_command(&command)?;
+                receive(self.transport.as_mut())
+            }
+            ok => ok,
+        }
+    }
+
+    /// Press and release `button`
+    pub fn click(&mut self, button: Button) -> Result<(), SwitchError> {
+        self.send_command(&format!("click {}", button))
+    }
+
+    /// Press and hold `button` until it is released
+    pub fn press(&mut self, button: Button) -> Result<(), SwitchError> {
+        self.send_command(&format!("press {}", button))
+    }
+
+    /// Release a held `button`
+    pub fn release(&mut self, button: Button) -> Result<(), SwitchError> {
+        self.send_command(&format!("release {}", button))
+    }
+
+    /// Hold `stick` at (`x`, `y`), each axis in `-32768..=32767`
+    pub fn set_stick(&mut self, stick: Stick, x: i32, y: i32) -> Result<(), SwitchError> {
+        let x = input::validate_stick_axis(x)?;
+        let y = input::validate_stick_axis(y)?;
+        self.send_command(&format!("setStick {} {} {}", stick, x, y))
+    }
+
+    /// Tap the touch screen at (`x`, `y`)
+    pub fn touch(&mut self, x: u32, y: u32) -> Result<(), SwitchError> {
+        input::validate_touch(x, y)?;
+        self.send_command(&format!("touch {} {}", x, y))
+    }
+
+    /// Detach the virtual controller so physical controllers work again
+    pub fn detach_controller(&mut self) -> Result<(), SwitchError> {
+        self.send_command("detachController")
+    }
+
+    /// Title ID of the game running in the foreground
+    pub fn title_id(&mut self) -> Result<u64, SwitchError> {
+        self.send_command("getTitleID")?;
+        self.receive(|t| t.receive_u64())
+    }
+
+    /// Build ID of the running game's main NSO, which tells releases of the
+    /// same title apart. Sysbot reports the first 8 bytes of it.
+    pub fn build_id(&mut self) -> Result<u64, SwitchError> {
+        self.send_command("getBuildID")?;
+        self.receive(|t| t.receive_u64())
+    }
+
+    /// Version of the sysbot module on the console
+    pub fn sysbot_version(&mut self) -> Result<String, SwitchError> {
+        self.send_command("getVersion")?;
+        self.receive(|t| t.receive_text())
+    }
+
+    /// Grab the current frame as the JPEG bytes sysbot sends
+    pub fn pixel_peek(&mut self) -> Result<Vec<u8>, SwitchError> {
+        self.send_command("pixelPeek")?;
+        self.receive(|t| t.receive_payload())
+    }
+
+    /// Grab and decode the current frame
+    pub fn screenshot(&mut self) -> Result<Screenshot, SwitchError> {
+        Screenshot::from_jpeg(&self.pixel_peek()?)
     }
 
     /// Write a signle DWORD to `address`
-    pub fn write_dword(&mut self, address: u32, value: u32) -> Result<(), Box<dyn Error>> {
-        self.write_bytes(address, &(value as u32).to_le_bytes())
+    pub fn write_dword(&mut self, address: Address, value: u32) -> Result<(), SwitchError> {
+        self.write_bytes(address, &value.to_le_bytes())
     }
 
     /// Write bytes from `buf` to `address` on the Switch
-    pub fn write_bytes(&mut self, address: u32, buf: &[u8]) -> Result<(), Box<dyn Error>> {
-        self.send_command(format!(
-            "poke 0x{:08x} 0x{}",
-            address,
-            buf.iter()
-                .map(|b: &u8| format!("{:02x}", &b))
-                .reduce(|a, x| a + &x)
-                .expect("Failed to format bytes")
-        ))
+    pub fn write_bytes(&mut self, address: Address, buf: &[u8]) -> Result<(), SwitchError> {
+        if buf.is_empty() {
+            return Err(SwitchError::Protocol("Cannot poke zero bytes".into()));
+        }
+        address.offset(buf.len() as u64)?;
+        let command = format!(
+            "{} 0x{:08x} 0x{}",
+            address.poke_command(),
+            address.value(),
+            hex::encode(buf)
+        );
+        self.verified_write(
+            &address.to_string(),
+            buf,
+            |switch| switch.send_command(&command),
+            |switch, actual| switch.read_bytes(address, actual, actual.len() as u32),
+        )
+    }
+
+    /// Choose whether every write is read back and compared, poking again up
+    /// to `retries` more times before giving up with
+    /// [`SwitchError::WriteMismatch`]. `None`, the default, trusts each poke.
+    pub fn set_verified_writes(&mut self, retries: Option<u32>) {
+        self.write_retries = retries;
+    }
+
+    /// How many times a write that reads back wrong is retried, if writes are
+    /// verified at all
+    pub fn verified_writes(&self) -> Option<u32> {
+        self.write_retries
+    }
+
+    /// Run `write`, then if writes are verified, `read` the range back and
+    /// repeat the write until it holds `expected` or the retries run out
+    fn verified_write<W, R>(
+        &mut self,
+        target: &str,
+        expected: &[u8],
+        mut write: W,
+        mut read: R,
+    ) -> Result<(), SwitchError>
+    where
+        W: FnMut(&mut Self) -> Result<(), SwitchError>,
+        R: FnMut(&mut Self, &mut [u8]) -> Result<(), SwitchError>,
+    {
+        write(self)?;
+        let Some(retries) = self.write_retries else {
+            return Ok(());
+        };
+        let mut actual = vec![0; expected.len()];
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                write(self)?;
+            }
+            read(self, &mut actual)?;
+            if actual == expected {
+                return Ok(());
+            }
+        }
+        Err(SwitchError::WriteMismatch {
+            target: target.to_string(),
+            attempts: retries + 1,
+            expected: expected.to_vec(),
+            actual,
+        })
     }
 
     /// Read `length` bytes from `address` into a buffer `buf`
     pub fn read_bytes(
         &mut self,
-        address: u32,
+        address: Address,
         buf: &mut [u8],
         length: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        self.send_command(format!("peek 0x{:08x} 0x{:08x}", address, length))?;
-        receive_bytes(&mut self.switch_handle, &self.read_endpoint, buf, length)?;
+    ) -> Result<(), SwitchError> {
+        address.offset(length as u64)?;
+        self.send_command(&format!(
+            "{} 0x{:08x} 0x{:08x}",
+            address.peek_command(),
+            address.value(),
+            length
+        ))?;
+        let buf = buf.get_mut(..length as usize).ok_or_else(|| {
+            SwitchError::Protocol(format!("Buffer too small to read {} bytes", length))
+        })?;
+        self.receive(|t| t.receive_bytes(buf))
+    }
+
+    /// Set the largest single read and write the chunked helpers will issue
+    pub fn set_chunk_sizes(&mut self, read_chunk_size: usize, write_chunk_size: usize) {
+        self.read_chunk_size = read_chunk_size.max(1);
+        self.write_chunk_size = write_chunk_size.max(1);
+    }
+
+    /// Read `buf.len()` bytes from `address` in sysbot-sized pieces, calling
+    /// `progress(done, total)` after each one
+    pub fn read_bytes_chunked<F: FnMut(usize, usize)>(
+        &mut self,
+        address: Address,
+        buf: &mut [u8],
+        mut progress: F,
+    ) -> Result<(), SwitchError> {
+        let total = buf.len();
+        let chunk_size = self.read_chunk_size;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let length = chunk.len() as u32;
+            self.read_bytes(address.offset((i * chunk_size) as u64)?, chunk, length)?;
+            progress(i * chunk_size + chunk.len(), total);
+        }
         Ok(())
     }
-}
 
-#[derive(Debug)]
-struct Endpoint {
-    config: u8,
-    iface: u8,
-    setting: u8,
-    address: u8,
-}
+    /// Write `buf` to `address` in sysbot-sized pieces, calling
+    /// `progress(done, total)` after each one
+    pub fn write_bytes_chunked<F: FnMut(usize, usize)>(
+        &mut self,
+        address: Address,
+        buf: &[u8],
+        mut progress: F,
+    ) -> Result<(), SwitchError> {
+        let total = buf.len();
+        let chunk_size = self.write_chunk_size;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            self.write_bytes(address.offset((i * chunk_size) as u64)?, chunk)?;
+            progress(i * chunk_size + chunk.len(), total);
+        }
+        Ok(())
+    }
 
-fn get_switch_enpoint(
-    switch_descriptor: &DeviceDescriptor,
-    switch_device: &Device<Context>,
-    direction: Direction,
-) -> Option<Endpoint> {
-    for i in 0..switch_descriptor.num_configurations() {
-        let config_desc = match switch_device.config_descriptor(i) {
-            Ok(d) => d,
-            Err(_) => continue,
-        };
+    /// Choose whether batched reads use sysbot's `peekMulti` family (the
+    /// default) or one `peek` per range. Sysbots without `peekMulti` are
+    /// detected on the first batch it fails for, so this is only needed to
+    /// skip that first attempt.
+    pub fn set_multi_peek(&mut self, enabled: bool) {
+        self.multi_peek = enabled;
+    }
+
+    /// Read many `(address, length)` ranges, returning one buffer per range.
+    ///
+    /// Overlapping and adjacent ranges are merged, and small ranges in the same
+    /// address space share a single `peekMulti` round-trip, with replies no
+    /// bigger than the read chunk size.
+    pub fn read_batch(&mut self, ranges: &[(Address, usize)]) -> Result<Vec<Vec<u8>>, SwitchError> {
+        let spans = batch::coalesce(
+            &ranges
+                .iter()
+                .map(|(address, length)| (*address, *length as u64))
+                .collect::<Vec<_>>(),
+        )?;
+
+        let mut span_data: Vec<Vec<u8>> = spans.iter().map(|s| vec![0; s.length as usize]).collect();
+        let mut pending: Vec<usize> = Vec::new();
+        let mut pending_length = 0;
+        for (i, span) in spans.iter().enumerate() {
+            if span.length == 0 {
+                continue;
+            }
+            let length = span.length as usize;
+            if self.multi_peek && length <= self.read_chunk_size {
+                if pending
+                    .last()
+                    .is_some_and(|&p| batch::space(&spans[p].address) != batch::space(&span.address))
+                    || pending.len() == MAX_MULTI_PEEK_RANGES
+                    || pending_length + length > self.read_chunk_size
+                {
+                    self.peek_multi(&spans, &pending, &mut span_data)?;
+                    pending.clear();
+                    pending_length = 0;
+                }
+                pending.push(i);
+                pending_length += length;
+            } else {
+                self.read_bytes_chunked(span.address, &mut span_data[i], |_, _| {})?;
+            }
+        }
+        self.peek_multi(&spans, &pending, &mut span_data)?;
+
+        let mut results = vec![Vec::new(); ranges.len()];
+        for (span, data) in spans.iter().zip(&span_data) {
+            for &member in &span.members {
+                let (address, length) = ranges[member];
+                let start = (address.value() - span.address.value()) as usize;
+                results[member] = data[start..start + length].to_vec();
+            }
+        }
+        Ok(results)
+    }
 
-        for interface in config_desc.interfaces() {
-            for interface_desc in interface.descriptors() {
-                for endpoint_desc in interface_desc.endpoint_descriptors() {
-                    if endpoint_desc.direction() == direction {
-                        return Some(Endpoint {
-                            config: config_desc.number(),
-                            iface: interface.number(),
-                            setting: interface_desc.setting_number(),
-                            address: endpoint_desc.address(),
-                        });
+    /// Read the spans at `indices`, all in one address space, in one command.
+    /// If the sysbot rejects `peekMulti`, it's turned off and the spans are
+    /// read one at a time instead. A reply that doesn't come in time is
+    /// waited for once more before reconnecting and reading the spans one at
+    /// a time, this once.
+    fn peek_multi(
+        &mut self,
+        spans: &[batch::Span],
+        indices: &[usize],
+        span_data: &mut [Vec<u8>],
+    ) -> Result<(), SwitchError> {
+        match indices {
+            [] => Ok(()),
+            [i] => {
+                let length = spans[*i].length as u32;
+                self.read_bytes(spans[*i].address, &mut span_data[*i], length)
+            }
+            _ => {
+                let args: Vec<String> = indices
+                    .iter()
+                    .map(|&i| format!("0x{:08x} 0x{:08x}", spans[i].address.value(), spans[i].length))
+                    .collect();
+                let total = indices.iter().map(|&i| spans[i].length as usize).sum();
+                let mut reply = vec![0; total];
+                let result = self
+                    .send_command(&format!(
+                        "{} {}",
+                        batch::peek_multi_command(&spans[indices[0]].address),
+                        args.join(" ")
+                    ))
+                    .and_then(|_| self.receive(|t| t.receive_bytes(&mut reply)));
+                let result = match result {
+                    // A slow reply is still on its way, and would otherwise be
+                    // taken as the reply to the next command
+                    Err(SwitchError::Timeout) => self.transport.receive_bytes(&mut reply),
+                    result => result,
+                };
+                match result {
+                    Err(SwitchError::Protocol(_)) => {
+                        self.multi_peek = false;
+                        return self.peek_each(spans, indices, span_data);
+                    }
+                    Err(SwitchError::Timeout | SwitchError::ShortRead { .. }) => {
+                        // Start over on a clean stream, but keep peekMulti: it
+                        // was slow, not rejected
+                        self.reconnect()?;
+                        return self.peek_each(spans, indices, span_data);
                     }
+                    result => result?,
+                }
+                let mut rest = reply.as_slice();
+                for &i in indices {
+                    let (data, tail) = rest.split_at(spans[i].length as usize);
+                    span_data[i].copy_from_slice(data);
+                    rest = tail;
                 }
+                Ok(())
             }
         }
     }
-    None
-}
 
-fn configure_endpoint(
-    switch_handle: &mut DeviceHandle<Context>,
-    endpoint: &Endpoint,
-) -> Result<(), Box<dyn Error>> {
-    switch_handle.set_active_configuration(endpoint.config)?;
-    switch_handle.claim_interface(endpoint.iface)?;
-    switch_handle.set_alternate_setting(endpoint.iface, endpoint.setting)?;
-    Ok(())
-}
+    fn peek_each(
+        &mut self,
+        spans: &[batch::Span],
+        indices: &[usize],
+        span_data: &mut [Vec<u8>],
+    ) -> Result<(), SwitchError> {
+        for &i in indices {
+            self.peek_multi(spans, &[i], span_data)?;
+        }
+        Ok(())
+    }
+
+    /// Write many `(address, bytes)` operations, merging overlapping and
+    /// adjacent ones into as few pokes as possible. Where operations overlap,
+    /// later ones win.
+    pub fn write_batch(&mut self, writes: &[(Address, Vec<u8>)]) -> Result<(), SwitchError> {
+        let spans = batch::coalesce(
+            &writes
+                .iter()
+                .map(|(address, data)| (*address, data.len() as u64))
+                .collect::<Vec<_>>(),
+        )?;
+
+        for mut span in spans {
+            let mut data = vec![0; span.length as usize];
+            span.members.sort_unstable();
+            for member in span.members {
+                let (address, bytes) = &writes[member];
+                let start = (address.value() - span.address.value()) as usize;
+                data[start..start + bytes.len()].copy_from_slice(bytes);
+            }
+            if !data.is_empty() {
+                self.write_bytes_chunked(span.address, &data, |_, _| {})?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Choose whether main-based pointer chains are resolved by sysbot
+    /// (`pointerAll`/`pointerPeek`/`pointerPoke`, the default) or by following
+    /// them here with absolute peeks, for sysbots without pointer commands
+    pub fn set_server_pointers(&mut self, enabled: bool) {
+        self.server_pointers = enabled;
+    }
+
+    fn server_jumps(&self, chain: &PointerChain) -> Option<String> {
+        self.server_pointers
+            .then(|| chain.sysbot_jumps())
+            .flatten()
+    }
+
+    /// Resolve `chain` to the absolute address it points at
+    pub fn resolve_pointer(&mut self, chain: &PointerChain) -> Result<Address, SwitchError> {
+        match self.server_jumps(chain) {
+            Some(jumps) => {
+                self.send_command(&format!("pointerAll {}", jumps))?;
+                Ok(Address::Absolute(self.receive(|t| t.receive_u64())?))
+            }
+            None => self.resolve_pointer_locally(chain),
+        }
+    }
 
-fn send_command(
-    switch_handle: &mut DeviceHandle<Context>,
-    write_endpoint: &Endpoint,
-    command: String,
-) -> Result<(), Box<dyn Error>> {
-    configure_endpoint(switch_handle, &write_endpoint)?;
-    let bytes_to_send = ((command.len() + 2) as u32).to_le_bytes();
-    switch_handle.write_bulk(
-        write_endpoint.address,
-        &bytes_to_send,
-        Duration::from_secs(5),
-    )?;
-    switch_handle.write_bulk(
-        write_endpoint.address,
-        (command + "\r\n").as_bytes(),
-        Duration::from_secs(5),
-    )?;
-    Ok(())
+    /// Resolve `chain` by reading each pointer along it with absolute peeks
+    pub fn resolve_pointer_locally(&mut self, chain: &PointerChain) -> Result<Address, SwitchError> {
+        let mut address = chain.base;
+        for (depth, offset) in chain.offsets.iter().enumerate() {
+            let mut pointer = [0u8; 8];
+            self.read_bytes(address, &mut pointer, 8)?;
+            let pointer = u64::from_le_bytes(pointer);
+            if pointer == 0 {
+                return Err(SwitchError::NullPointer {
+                    chain: chain.to_string(),
+                    depth,
+                });
+            }
+            address = Address::Absolute(pointer::apply_offset(pointer, *offset)?);
+        }
+        Ok(address)
+    }
+
+    /// Read `buf.len()` bytes from where `chain` points
+    pub fn read_pointer(&mut self, chain: &PointerChain, buf: &mut [u8]) -> Result<(), SwitchError> {
+        match self.server_jumps(chain) {
+            Some(jumps) => {
+                self.send_command(&format!("pointerPeek 0x{:x} {}", buf.len(), jumps))?;
+                self.receive(|t| t.receive_bytes(buf))
+            }
+            None => {
+                let address = self.resolve_pointer_locally(chain)?;
+                self.read_bytes(address, buf, buf.len() as u32)
+            }
+        }
+    }
+
+    /// Write `buf` to where `chain` points
+    pub fn write_pointer(&mut self, chain: &PointerChain, buf: &[u8]) -> Result<(), SwitchError> {
+        match self.server_jumps(chain) {
+            Some(jumps) => {
+                if buf.is_empty() {
+                    return Err(SwitchError::Protocol("Cannot poke zero bytes".into()));
+                }
+                let command = format!(
+                    "pointerPoke 0x{} {}",
+                    hex::encode(buf),
+                    jumps
+                );
+                let peek = format!("pointerPeek 0x{:x} {}", buf.len(), jumps);
+                self.verified_write(
+                    &chain.to_string(),
+                    buf,
+                    |switch| switch.send_command(&command),
+                    |switch, actual| {
+                        switch.send_command(&peek)?;
+                        switch.receive(|t| t.receive_bytes(actual))
+                    },
+                )
+            }
+            None => {
+                let address = self.resolve_pointer_locally(chain)?;
+                self.write_bytes(address, buf)
+            }
+        }
+    }
 }
 
-fn receive_bytes(
-    switch_handle: &mut DeviceHandle<Context>,
-    read_endpoint: &Endpoint,
-    buf: &mut [u8],
-    length: u32,
-) -> Result<(), Box<dyn Error>> {
-    configure_endpoint(switch_handle, &read_endpoint)?;
-
-    let mut size_recv: [u8; 4] = [0; 4];
-    switch_handle.read_bulk(
-        read_endpoint.address,
-        &mut size_recv,
-        Duration::from_secs(5),
-    )?;
-
-    if u32::from_le_bytes(size_recv) != length {
-        println!(
-            "Warning: Receiving {} bytes from switch... Expected: {}... aborting",
-            u32::from_le_bytes(size_recv),
-            length
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_connection() {
+        assert_eq!("usb".parse::<Connection>().unwrap(), Connection::Usb(None));
+        assert_eq!(
+            "usb:3-1.4".parse::<Connection>().unwrap(),
+            Connection::Usb(Some(UsbPort {
+                bus: 3,
+                ports: vec![1, 4]
+            }))
+        );
+        assert!("usb:3".parse::<Connection>().is_err());
+        assert!("usb:3-x".parse::<Connection>().is_err());
+        assert_eq!(
+            "emulator".parse::<Connection>().unwrap(),
+            Connection::Emulator
+        );
+        assert_eq!(
+            "replay:bug.log".parse::<Connection>().unwrap(),
+            Connection::Replay(PathBuf::from("bug.log"))
+        );
+        assert_eq!(
+            "192.168.0.10".parse::<Connection>().unwrap(),
+            Connection::Network {
+                host: "192.168.0.10".into(),
+                port: DEFAULT_PORT
+            }
+        );
+        assert_eq!(
+            "switch.lan:6001".parse::<Connection>().unwrap(),
+            Connection::Network {
+                host: "switch.lan".into(),
+                port: 6001
+            }
+        );
+        for (address, host, port) in [
+            ("fe80::1", "fe80::1", DEFAULT_PORT),
+            ("[fe80::1]", "fe80::1", DEFAULT_PORT),
+            ("[fe80::1]:6001", "fe80::1", 6001),
+        ] {
+            assert_eq!(
+                address.parse::<Connection>().unwrap(),
+                Connection::Network {
+                    host: host.into(),
+                    port
+                }
+            );
+        }
+        assert!("[fe80::1".parse::<Connection>().is_err());
+        assert!("[fe80::1]6001".parse::<Connection>().is_err());
+        assert!("[]:6001".parse::<Connection>().is_err());
+        assert!(":6000".parse::<Connection>().is_err());
+        assert!("switch.lan:port".parse::<Connection>().is_err());
+    }
+
+    #[test]
+    fn switch_peek_and_poke() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        switch.write_dword(Address::Heap(0x100), 0xdeadbeef).unwrap();
+        assert_eq!(emulator.read(Address::Heap(0x100), 4), vec![0xef, 0xbe, 0xad, 0xde]);
+
+        let mut buf = [0u8; 4];
+        switch.read_bytes(Address::Heap(0x100), &mut buf, 4).unwrap();
+        assert_eq!(u32::from_le_bytes(buf), 0xdeadbeef);
+
+        assert_eq!(
+            emulator.commands(),
+            vec!["poke 0x00000100 0xefbeadde", "peek 0x00000100 0x00000004"]
+        );
+    }
+
+    #[test]
+    fn chunked_transfers_reassemble() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+        switch.set_chunk_sizes(16, 8);
+
+        let data: Vec<u8> = (0..100).collect();
+        let mut writes = Vec::new();
+        switch
+            .write_bytes_chunked(Address::Heap(0x1000), &data, |done, total| writes.push((done, total)))
+            .unwrap();
+        assert_eq!(writes.len(), 13);
+        assert_eq!(writes[0], (8, 100));
+        assert_eq!(writes[12], (100, 100));
+        assert_eq!(emulator.read(Address::Heap(0x1000), 100), data);
+
+        let mut buf = vec![0u8; 100];
+        let mut reads = Vec::new();
+        switch
+            .read_bytes_chunked(Address::Heap(0x1000), &mut buf, |done, total| reads.push((done, total)))
+            .unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(reads, [16, 32, 48, 64, 80, 96, 100].map(|d| (d, 100)));
+        assert_eq!(
+            emulator.commands().last().unwrap(),
+            "peek 0x00001060 0x00000004"
+        );
+    }
+
+    #[test]
+    fn switch_title_and_build() {
+        let emulator = Emulator::new();
+        emulator.set_title(0x0100_0000_0000_1000, 0x1234);
+        let mut switch = Switch::with_transport(emulator);
+        assert_eq!(switch.title_id().unwrap(), 0x0100_0000_0000_1000);
+        assert_eq!(switch.build_id().unwrap(), 0x1234);
+        assert_eq!(switch.sysbot_version().unwrap(), emulator::EMULATOR_VERSION);
+    }
+
+    #[test]
+    fn verified_writes_retry_lost_pokes() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+        switch.set_verified_writes(Some(2));
+
+        emulator.lose_pokes(2);
+        switch.write_dword(Address::Heap(0x100), 0xdeadbeef).unwrap();
+        assert_eq!(emulator.read(Address::Heap(0x100), 4), vec![0xef, 0xbe, 0xad, 0xde]);
+        let pokes = emulator.commands().iter().filter(|c| c.starts_with("poke")).count();
+        assert_eq!(pokes, 3);
+
+        emulator.lose_pokes(3);
+        match switch.write_bytes(Address::Heap(0x200), &[1, 2]) {
+            Err(SwitchError::WriteMismatch {
+                attempts, actual, ..
+            }) => {
+                assert_eq!(attempts, 3);
+                assert_eq!(actual, vec![0, 0]);
+            }
+            other => panic!("expected a write mismatch, got {:?}", other),
+        }
+
+        // Unverified, a lost poke goes unnoticed
+        switch.set_verified_writes(None);
+        emulator.lose_pokes(1);
+        switch.write_bytes(Address::Heap(0x300), &[1]).unwrap();
+        assert_eq!(emulator.read(Address::Heap(0x300), 1), vec![0]);
+    }
+
+    #[test]
+    fn dropped_link_reconnects_transparently() {
+        let emulator = Emulator::new();
+        emulator.write(Address::Heap(0x10), &[7; 4]);
+        let mut switch = Switch::with_transport(emulator.clone());
+        let events = switch.subscribe();
+
+        emulator.drop_link();
+        let mut buf = [0u8; 4];
+        switch.read_bytes(Address::Heap(0x10), &mut buf, 4).unwrap();
+        assert_eq!(buf, [7; 4]);
+        assert_eq!(switch.state(), ConnectionState::Connected);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                ConnectionState::Disconnected,
+                ConnectionState::Reconnecting,
+                ConnectionState::Connected
+            ]
         );
     }
-    // println!("Receiving {} bytes from switch... Expected: {}", u32::from_le_bytes(size_recv), length);
 
-    switch_handle.read_bulk(read_endpoint.address, buf, Duration::from_secs(30))?;
+    #[test]
+    fn unplugged_console_reconnects_when_it_returns() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        emulator.set_plugged_in(false);
+        assert_eq!(switch.poll_connection(), ConnectionState::Disconnected);
+        assert!(matches!(
+            switch.click(Button::A),
+            Err(SwitchError::Disconnected)
+        ));
 
-    Ok(())
+        emulator.set_plugged_in(true);
+        assert_eq!(switch.poll_connection(), ConnectionState::Connected);
+        switch.click(Button::A).unwrap();
+        assert_eq!(emulator.commands(), vec!["click A"]);
+    }
 }
@@ -0,0 +1,152 @@
+use std::{fmt, str::FromStr};
+
+use super::{PointerChain, SwitchError};
+
+/// A location in the Switch's memory, in one of the address spaces sysbot
+/// understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// Relative to the start of the game's heap (`peek`/`poke`)
+    Heap(u64),
+    /// Relative to the start of the game's main NSO (`peekMain`/`pokeMain`)
+    Main(u64),
+    /// An absolute address (`peekAbsolute`/`pokeAbsolute`)
+    Absolute(u64),
+}
+
+impl Address {
+    /// The address within its space
+    pub fn value(&self) -> u64 {
+        match *self {
+            Address::Heap(a) | Address::Main(a) | Address::Absolute(a) => a,
+        }
+    }
+
+    /// The same kind of address, `delta` bytes further on
+    pub fn offset(&self, delta: u64) -> Result<Address, SwitchError> {
+        let value = self
+            .value()
+            .checked_add(delta)
+            .ok_or(SwitchError::AddressOverflow {
+                address: *self,
+                delta,
+            })?;
+        Ok(self.with_value(value))
+    }
+
+    /// The same kind of address, pointing at `value` instead
+    pub fn with_value(&self, value: u64) -> Address {
+        match self {
+            Address::Heap(_) => Address::Heap(value),
+            Address::Main(_) => Address::Main(value),
+            Address::Absolute(_) => Address::Absolute(value),
+        }
+    }
+
+    /// The sysbot command that reads from this address space
+    pub fn peek_command(&self) -> &'static str {
+        match self {
+            Address::Heap(_) => "peek",
+            Address::Main(_) => "peekMain",
+            Address::Absolute(_) => "peekAbsolute",
+        }
+    }
+
+    /// The sysbot command that writes to this address space
+    pub fn poke_command(&self) -> &'static str {
+        match self {
+            Address::Heap(_) => "poke",
+            Address::Main(_) => "pokeMain",
+            Address::Absolute(_) => "pokeAbsolute",
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Heap(a) => write!(f, "heap+0x{:08x}", a),
+            Address::Main(a) => write!(f, "main+0x{:08x}", a),
+            Address::Absolute(a) => write!(f, "0x{:016x}", a),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = SwitchError;
+
+    /// Parse `heap+X`, `main+X` or a bare absolute `X`, all in hex
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chain: PointerChain = s.parse()?;
+        if chain.depth() != 0 {
+            return Err(SwitchError::InvalidArgument(format!(
+                "{:?} is a pointer chain, not an address",
+                s
+            )));
+        }
+        Ok(chain.base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, Switch, EMULATOR_HEAP_BASE};
+
+    #[test]
+    fn offset_is_overflow_checked() {
+        assert_eq!(Address::Main(0x10).offset(0x20).unwrap(), Address::Main(0x30));
+        assert!(matches!(
+            Address::Absolute(u64::MAX - 1).offset(2),
+            Err(SwitchError::AddressOverflow { delta: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Address::Heap(0xAFB1E6E0).to_string(), "heap+0xafb1e6e0");
+        assert_eq!(Address::Absolute(0x80_0400_0000).to_string(), "0x0000008004000000");
+    }
+
+    #[test]
+    fn parse_round_trips_display() {
+        for address in [Address::Heap(0xAFB1E6E0), Address::Main(0x10), Address::Absolute(0x80_0400_0000)] {
+            assert_eq!(address.to_string().parse::<Address>().unwrap(), address);
+        }
+        assert!("[main+0x10]+0x8".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn address_spaces_are_separate() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        switch.write_bytes(Address::Heap(0x10), &[1]).unwrap();
+        switch.write_bytes(Address::Main(0x10), &[2]).unwrap();
+        switch.write_bytes(Address::Absolute(0x10), &[3]).unwrap();
+
+        let mut buf = [0u8; 1];
+        switch.read_bytes(Address::Main(0x10), &mut buf, 1).unwrap();
+        assert_eq!(buf, [2]);
+        switch.read_bytes(Address::Absolute(0x10), &mut buf, 1).unwrap();
+        assert_eq!(buf, [3]);
+        assert_eq!(emulator.read(Address::Heap(0x10), 1), vec![1]);
+        switch
+            .read_bytes(Address::Absolute(EMULATOR_HEAP_BASE + 0x10), &mut buf, 1)
+            .unwrap();
+        assert_eq!(buf, [1]);
+
+        assert_eq!(
+            emulator.commands()[3..],
+            [
+                "peekMain 0x00000010 0x00000001",
+                "peekAbsolute 0x00000010 0x00000001",
+                "peekAbsolute 0x400000010 0x00000001"
+            ]
+        );
+        assert!(matches!(
+            switch.read_bytes(Address::Absolute(u64::MAX), &mut buf, 1),
+            Err(SwitchError::AddressOverflow { .. })
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use super::{Address, SwitchError};
+
+/// A contiguous range in one address space, built from one or more requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Span {
+    pub address: Address,
+    pub length: u64,
+    /// Indices of the requests that fall inside this span
+    pub members: Vec<usize>,
+}
+
+impl Span {
+    fn end(&self) -> u64 {
+        self.address.value() + self.length
+    }
+}
+
+/// Group `(address, length)` ranges into spans, merging ranges in the same
+/// address space that overlap or touch. Spans come out sorted by space, then
+/// address.
+pub(super) fn coalesce(ranges: &[(Address, u64)]) -> Result<Vec<Span>, SwitchError> {
+    let mut order: Vec<usize> = (0..ranges.len()).collect();
+    for (address, length) in ranges {
+        address.offset(*length)?;
+    }
+    order.sort_by_key(|&i| (space(&ranges[i].0), ranges[i].0.value()));
+
+    let mut spans: Vec<Span> = Vec::new();
+    for i in order {
+        let (address, length) = ranges[i];
+        match spans.last_mut() {
+            Some(span)
+                if space(&span.address) == space(&address) && address.value() <= span.end() =>
+            {
+                span.length = span.length.max(address.value() + length - span.address.value());
+                span.members.push(i);
+            }
+            _ => spans.push(Span {
+                address,
+                length,
+                members: vec![i],
+            }),
+        }
+    }
+    Ok(spans)
+}
+
+/// Orders and tells apart the address spaces
+pub(super) fn space(address: &Address) -> u8 {
+    match address {
+        Address::Heap(_) => 0,
+        Address::Main(_) => 1,
+        Address::Absolute(_) => 2,
+    }
+}
+
+/// The sysbot command that reads several ranges of one address space at once
+pub(super) fn peek_multi_command(address: &Address) -> &'static str {
+    match address {
+        Address::Heap(_) => "peekMulti",
+        Address::Main(_) => "peekMainMulti",
+        Address::Absolute(_) => "peekAbsoluteMulti",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, Switch, Transport, DEFAULT_WRITE_CHUNK_SIZE};
+
+    /// Sits on the reply to the first `stalls` attempts to receive a
+    /// `peekMulti` reply, like a sysbot that's slow to answer one
+    struct StallingTransport {
+        emulator: Emulator,
+        stalls: u32,
+        multi: bool,
+    }
+
+    impl Transport for StallingTransport {
+        fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+            self.multi = command.starts_with("peekMulti");
+            self.emulator.send_command(command)
+        }
+
+        fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError> {
+            if self.multi && self.stalls > 0 {
+                self.stalls -= 1;
+                return Err(SwitchError::Timeout);
+            }
+            self.emulator.receive_bytes(buf)
+        }
+
+        fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError> {
+            self.emulator.receive_payload()
+        }
+
+        fn receive_u64(&mut self) -> Result<u64, SwitchError> {
+            self.emulator.receive_u64()
+        }
+
+        fn receive_text(&mut self) -> Result<String, SwitchError> {
+            self.emulator.receive_text()
+        }
+
+        fn reconnect(&mut self) -> Result<(), SwitchError> {
+            self.emulator.reconnect()
+        }
+    }
+
+    #[test]
+    fn merges_touching_and_overlapping_ranges() {
+        let spans = coalesce(&[
+            (Address::Heap(0x18), 8),
+            (Address::Heap(0x10), 8),
+            (Address::Main(0x14), 4),
+            (Address::Heap(0x1c), 2),
+            (Address::Heap(0x40), 4),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    address: Address::Heap(0x10),
+                    length: 0x10,
+                    members: vec![1, 0, 3]
+                },
+                Span {
+                    address: Address::Heap(0x40),
+                    length: 4,
+                    members: vec![4]
+                },
+                Span {
+                    address: Address::Main(0x14),
+                    length: 4,
+                    members: vec![2]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_ranges() {
+        assert!(coalesce(&[(Address::Absolute(u64::MAX), 2)]).is_err());
+    }
+
+    #[test]
+    fn batches_coalesce_into_few_commands() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        switch
+            .write_batch(&[
+                (Address::Heap(0x10), vec![1, 1, 1, 1]),
+                (Address::Heap(0x14), vec![2, 2]),
+                (Address::Heap(0x12), vec![3]),
+                (Address::Main(0x10), vec![4]),
+            ])
+            .unwrap();
+        assert_eq!(
+            emulator.commands(),
+            vec!["poke 0x00000010 0x010103010202", "pokeMain 0x00000010 0x04"]
+        );
+
+        let reads = switch
+            .read_batch(&[
+                (Address::Heap(0x14), 2),
+                (Address::Heap(0x10), 4),
+                (Address::Heap(0x100), 1),
+                (Address::Main(0x10), 1),
+            ])
+            .unwrap();
+        assert_eq!(reads, vec![vec![2, 2], vec![1, 1, 3, 1], vec![0], vec![4]]);
+        assert_eq!(
+            emulator.commands()[2..],
+            [
+                "peekMulti 0x00000010 0x00000006 0x00000100 0x00000001",
+                "peekMain 0x00000010 0x00000001"
+            ]
+        );
+
+        switch.set_multi_peek(false);
+        let reads = switch
+            .read_batch(&[(Address::Heap(0x10), 2), (Address::Heap(0x100), 1)])
+            .unwrap();
+        assert_eq!(reads, vec![vec![1, 1], vec![0]]);
+        assert_eq!(emulator.commands().len(), 6);
+    }
+
+    #[test]
+    fn batches_keep_replies_within_the_chunk_size() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+        switch.set_chunk_sizes(8, DEFAULT_WRITE_CHUNK_SIZE);
+
+        let ranges: Vec<(Address, usize)> =
+            (0..3).map(|i| (Address::Heap(0x100 * i), 4)).collect();
+        switch.read_batch(&ranges).unwrap();
+        assert_eq!(
+            emulator.commands(),
+            vec![
+                "peekMulti 0x00000000 0x00000004 0x00000100 0x00000004",
+                "peek 0x00000200 0x00000004"
+            ]
+        );
+    }
+
+    #[test]
+    fn batches_fall_back_without_peek_multi() {
+        let emulator = Emulator::new();
+        emulator.write(Address::Heap(0x100), &[7]);
+        emulator.set_multi_peek_supported(false);
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        let ranges = [(Address::Heap(0x10), 2), (Address::Heap(0x100), 1)];
+        assert_eq!(switch.read_batch(&ranges).unwrap(), vec![vec![0, 0], vec![7]]);
+        // Only the first batch tries peekMulti
+        switch.read_batch(&ranges).unwrap();
+        let multi = emulator.commands().iter().filter(|c| c.starts_with("peekMulti")).count();
+        assert_eq!(multi, 1);
+        assert_eq!(emulator.commands().len(), 5);
+    }
+
+    #[test]
+    fn timeouts_keep_peek_multi() {
+        let ranges = [(Address::Heap(0x10), 2), (Address::Heap(0x100), 1)];
+        for stalls in [1, 2] {
+            let emulator = Emulator::new();
+            emulator.write(Address::Heap(0x100), &[7]);
+            let mut switch = Switch::with_transport(StallingTransport {
+                emulator: emulator.clone(),
+                stalls,
+                multi: false,
+            });
+
+            // The late reply is picked up, or the link reset before the
+            // spans are read one at a time
+            assert_eq!(switch.read_batch(&ranges).unwrap(), vec![vec![0, 0], vec![7]]);
+            assert_eq!(switch.read_batch(&ranges).unwrap(), vec![vec![0, 0], vec![7]]);
+            let multi = emulator.commands().iter().filter(|c| c.starts_with("peekMulti")).count();
+            assert_eq!(multi, 2, "after {} stalls", stalls);
+        }
+    }
+}
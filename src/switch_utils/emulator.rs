@@ -0,0 +1,415 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use image::{codecs::jpeg::JpegEncoder, RgbImage};
+
+use super::{hex, input, pointer, Address, Button, Stick, SwitchError, Transport, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Where the emulated game's heap starts in absolute memory
+pub const EMULATOR_HEAP_BASE: u64 = 0x4_0000_0000;
+/// Where the emulated game's main NSO starts in absolute memory
+pub const EMULATOR_MAIN_BASE: u64 = 0x80_0000_0000;
+/// Title the emulator reports until told otherwise: Animal Crossing, since
+/// that's the game this project drives
+pub const EMULATOR_TITLE_ID: u64 = 0x0100_6F80_0232_6000;
+/// Build ID the emulator reports until told otherwise ("EMULATOR" in ASCII)
+pub const EMULATOR_BUILD_ID: u64 = 0x454D_554C_4154_4F52;
+/// Sysbot version the emulator reports
+pub(super) const EMULATOR_VERSION: &str = "emulator";
+
+/// In-process sysbot with an in-memory address space.
+///
+/// Cloning an `Emulator` gives another handle onto the same console, so tests
+/// can hand one clone to a `Switch` and inspect memory through another.
+/// Memory that was never written reads back as zeroes. The heap and main NSO
+/// live at [`EMULATOR_HEAP_BASE`] and [`EMULATOR_MAIN_BASE`] in one absolute
+/// address space.
+#[derive(Clone)]
+pub struct Emulator {
+    state: Arc<Mutex<EmulatorState>>,
+}
+
+#[derive(Default)]
+struct EmulatorState {
+    pages: HashMap<u64, Box<[u8]>>,
+    replies: VecDeque<Vec<u8>>,
+    commands: Vec<String>,
+    screen: Option<Vec<u8>>,
+    unplugged: bool,
+    link_down: bool,
+    /// Pokes still to be acknowledged without being applied
+    lost_pokes: usize,
+    /// Reject the `peekMulti` family, like older sysbots
+    no_multi_peek: bool,
+    title_id: u64,
+    build_id: u64,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Emulator::new()
+    }
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        let state = EmulatorState {
+            title_id: EMULATOR_TITLE_ID,
+            build_id: EMULATOR_BUILD_ID,
+            ..EmulatorState::default()
+        };
+        Emulator {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, EmulatorState> {
+        self.state.lock().expect("Emulator state poisoned")
+    }
+
+    /// Read `length` bytes of emulated memory starting at `address`
+    pub fn read(&self, address: Address, length: usize) -> Vec<u8> {
+        let address = absolute(address).expect("Address outside emulated memory");
+        self.state().read(address, length)
+    }
+
+    /// Write `buf` into emulated memory at `address`
+    pub fn write(&self, address: Address, buf: &[u8]) {
+        let address = absolute(address).expect("Address outside emulated memory");
+        self.state().write(address, buf)
+    }
+
+    /// Set the JPEG frame returned by `pixelPeek`
+    pub fn set_screen(&self, jpeg: Vec<u8>) {
+        self.state().screen = Some(jpeg);
+    }
+
+    /// Pretend a different game, or a different release of it, is running
+    pub fn set_title(&self, title_id: u64, build_id: u64) {
+        let mut state = self.state();
+        state.title_id = title_id;
+        state.build_id = build_id;
+    }
+
+    /// Every command received so far, in order
+    pub fn commands(&self) -> Vec<String> {
+        self.state().commands.clone()
+    }
+
+    /// Pretend the cable was pulled (`false`) or plugged back in (`true`).
+    /// While unplugged every transfer fails and reconnecting is refused.
+    pub fn set_plugged_in(&self, plugged_in: bool) {
+        let mut state = self.state();
+        state.unplugged = !plugged_in;
+        state.link_down |= !plugged_in;
+        state.replies.clear();
+    }
+
+    /// Pretend to be a sysbot with (`true`, the default) or without the
+    /// `peekMulti` family of commands
+    pub fn set_multi_peek_supported(&self, supported: bool) {
+        self.state().no_multi_peek = !supported;
+    }
+
+    /// Accept the next `count` pokes without applying them, as when a write
+    /// silently doesn't take
+    pub fn lose_pokes(&self, count: usize) {
+        self.state().lost_pokes = count;
+    }
+
+    /// Break the current connection without unplugging, as when the console
+    /// resets its USB stack: the next transfer fails but reconnecting works
+    pub fn drop_link(&self) {
+        let mut state = self.state();
+        state.link_down = true;
+        state.replies.clear();
+    }
+}
+
+impl EmulatorState {
+    fn read(&self, address: u64, length: usize) -> Vec<u8> {
+        (0..length as u64)
+            .map(|i| {
+                let a = address.wrapping_add(i);
+                self.pages
+                    .get(&(a / PAGE_SIZE))
+                    .map_or(0, |page| page[(a % PAGE_SIZE) as usize])
+            })
+            .collect()
+    }
+
+    fn write(&mut self, address: u64, buf: &[u8]) {
+        for (i, b) in buf.iter().enumerate() {
+            let a = address.wrapping_add(i as u64);
+            let page = self
+                .pages
+                .entry(a / PAGE_SIZE)
+                .or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+            page[(a % PAGE_SIZE) as usize] = *b;
+        }
+    }
+
+    /// Apply a poke, unless it's one of the ones to lose
+    fn poke(&mut self, address: u64, data: &[u8]) {
+        if self.lost_pokes > 0 {
+            self.lost_pokes -= 1;
+        } else {
+            self.write(address, data);
+        }
+    }
+
+    fn check_link(&self) -> Result<(), SwitchError> {
+        if self.link_down {
+            return Err(SwitchError::Disconnected);
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, command: &str) -> Result<(), SwitchError> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        match args.as_slice() {
+            [command @ ("peek" | "peekMain" | "peekAbsolute"), address, length] => {
+                let address = parse_address(command, address)?;
+                let reply = self.read(absolute(address)?, parse_number(length)? as usize);
+                self.replies.push_back(reply);
+            }
+            [command @ ("peekMulti" | "peekMainMulti" | "peekAbsoluteMulti"), ranges @ ..]
+                if !self.no_multi_peek && !ranges.is_empty() && ranges.len() % 2 == 0 =>
+            {
+                let single = command.trim_end_matches("Multi");
+                let mut reply = Vec::new();
+                for range in ranges.chunks(2) {
+                    let address = absolute(parse_address(single, range[0])?)?;
+                    reply.extend(self.read(address, parse_number(range[1])? as usize));
+                }
+                self.replies.push_back(reply);
+            }
+            [command @ ("poke" | "pokeMain" | "pokeAbsolute"), address, data] => {
+                let address = parse_address(command, address)?;
+                let data = parse_data(data)?;
+                self.poke(absolute(address)?, &data);
+            }
+            ["click" | "press" | "release", button] => {
+                button.parse::<Button>()?;
+            }
+            ["setStick", stick, x, y] => {
+                stick.parse::<Stick>()?;
+                input::validate_stick_axis(parse_signed(x)?)?;
+                input::validate_stick_axis(parse_signed(y)?)?;
+            }
+            ["touch", x, y] => {
+                input::validate_touch(parse_number(x)? as u32, parse_number(y)? as u32)?;
+            }
+            ["detachController"] => {}
+            ["getTitleID"] => self.replies.push_back(self.title_id.to_le_bytes().to_vec()),
+            ["getBuildID"] => self.replies.push_back(self.build_id.to_le_bytes().to_vec()),
+            ["getVersion"] => self.replies.push_back(EMULATOR_VERSION.as_bytes().to_vec()),
+            ["pointerAll", jumps @ ..] => {
+                let address = self.follow_main_pointer(jumps)?;
+                self.replies.push_back(address.to_le_bytes().to_vec());
+            }
+            ["pointerPeek", length, jumps @ ..] => {
+                let address = self.follow_main_pointer(jumps)?;
+                let reply = self.read(address, parse_number(length)? as usize);
+                self.replies.push_back(reply);
+            }
+            ["pointerPoke", data, jumps @ ..] => {
+                let address = self.follow_main_pointer(jumps)?;
+                let data = parse_data(data)?;
+                self.poke(address, &data);
+            }
+            ["pixelPeek"] => {
+                let screen = self.screen.get_or_insert_with(test_pattern).clone();
+                self.replies.push_back(screen);
+            }
+            _ => {
+                return Err(SwitchError::Protocol(format!(
+                    "Emulator does not understand {:?}",
+                    command
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EmulatorState {
+    /// Resolve sysbot pointer jumps: main offset, intermediate jumps, final offset
+    fn follow_main_pointer(&self, jumps: &[&str]) -> Result<u64, SwitchError> {
+        let jumps = jumps
+            .iter()
+            .map(|j| parse_jump(j))
+            .collect::<Result<Vec<i64>, SwitchError>>()?;
+        let (final_jump, jumps) = match jumps.split_last() {
+            Some((final_jump, jumps)) if !jumps.is_empty() => (*final_jump, jumps),
+            _ => return Err(SwitchError::Protocol("Pointer needs at least two jumps".into())),
+        };
+
+        let mut address = EMULATOR_MAIN_BASE;
+        for jump in jumps {
+            address = pointer::apply_offset(address, *jump)?;
+            address = u64::from_le_bytes(self.read(address, 8).try_into().unwrap());
+            if address == 0 {
+                return Err(SwitchError::Protocol("Pointer chain hit null".into()));
+            }
+        }
+        pointer::apply_offset(address, final_jump)
+    }
+}
+
+impl Transport for Emulator {
+    fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+        let mut state = self.state();
+        state.check_link()?;
+        state.commands.push(command.to_string());
+        state.execute(command)
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError> {
+        let reply = self.receive_payload()?;
+        if reply.len() != buf.len() {
+            return Err(SwitchError::SizeMismatch {
+                expected: buf.len(),
+                announced: reply.len(),
+            });
+        }
+        buf.copy_from_slice(&reply);
+        Ok(())
+    }
+
+    fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError> {
+        let mut state = self.state();
+        state.check_link()?;
+        state
+            .replies
+            .pop_front()
+            .ok_or_else(|| SwitchError::Protocol("No reply pending from emulator".into()))
+    }
+
+    fn receive_u64(&mut self) -> Result<u64, SwitchError> {
+        let mut buf = [0u8; 8];
+        self.receive_bytes(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn receive_text(&mut self) -> Result<String, SwitchError> {
+        String::from_utf8(self.receive_payload()?)
+            .map_err(|_| SwitchError::Protocol("Emulator reply isn't UTF-8".into()))
+    }
+
+    fn reconnect(&mut self) -> Result<(), SwitchError> {
+        let mut state = self.state();
+        if state.unplugged {
+            return Err(SwitchError::DeviceNotFound);
+        }
+        state.link_down = false;
+        // Replies nobody read went with the old link
+        state.replies.clear();
+        Ok(())
+    }
+
+    fn is_present(&mut self) -> Option<bool> {
+        Some(!self.state().unplugged)
+    }
+}
+
+/// A full-size gradient frame, so screenshot code has something to show
+fn test_pattern() -> Vec<u8> {
+    let image = RgbImage::from_fn(SCREEN_WIDTH, SCREEN_HEIGHT, |x, y| {
+        image::Rgb([
+            (x * 255 / SCREEN_WIDTH) as u8,
+            (y * 255 / SCREEN_HEIGHT) as u8,
+            0x80,
+        ])
+    });
+    let mut jpeg = Vec::new();
+    JpegEncoder::new(&mut jpeg)
+        .encode_image(&image)
+        .expect("Failed to encode emulator test pattern");
+    jpeg
+}
+
+/// Where `address` lives in the emulator's absolute address space
+fn absolute(address: Address) -> Result<u64, SwitchError> {
+    match address {
+        Address::Heap(a) => Address::Absolute(EMULATOR_HEAP_BASE).offset(a),
+        Address::Main(a) => Address::Absolute(EMULATOR_MAIN_BASE).offset(a),
+        Address::Absolute(_) => Ok(address),
+    }
+    .map(|a| a.value())
+}
+
+/// Parse the address argument of a peek/poke variant
+fn parse_address(command: &str, arg: &str) -> Result<Address, SwitchError> {
+    let value = parse_number(arg)?;
+    Ok(match command {
+        "peek" | "poke" => Address::Heap(value),
+        "peekMain" | "pokeMain" => Address::Main(value),
+        _ => Address::Absolute(value),
+    })
+}
+
+/// Parse a sysbot numeric argument (`0x` hex or decimal)
+fn parse_number(arg: &str) -> Result<u64, SwitchError> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+    .map_err(|_| SwitchError::Protocol(format!("Invalid number {:?}", arg)))
+}
+
+/// Parse a signed decimal sysbot argument
+fn parse_signed(arg: &str) -> Result<i32, SwitchError> {
+    arg.parse()
+        .map_err(|_| SwitchError::Protocol(format!("Invalid number {:?}", arg)))
+}
+
+/// Parse a possibly negative pointer jump (`0x10`, `-0x10` or decimal)
+fn parse_jump(arg: &str) -> Result<i64, SwitchError> {
+    let (negative, magnitude) = match arg.strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, arg),
+    };
+    let value = i64::try_from(parse_number(magnitude)?)
+        .map_err(|_| SwitchError::Protocol(format!("Invalid jump {:?}", arg)))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Parse a sysbot data argument (`0x` followed by hex bytes)
+fn parse_data(arg: &str) -> Result<Vec<u8>, SwitchError> {
+    arg.strip_prefix("0x")
+        .and_then(hex::decode)
+        .ok_or_else(|| SwitchError::Protocol(format!("Invalid data {:?}", arg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwritten_memory_reads_as_zero() {
+        let emulator = Emulator::new();
+        assert_eq!(emulator.read(Address::Heap(0x1234), 4), vec![0; 4]);
+    }
+
+    #[test]
+    fn writes_span_pages() {
+        let emulator = Emulator::new();
+        emulator.write(Address::Absolute(PAGE_SIZE - 2), &[1, 2, 3, 4]);
+        assert_eq!(
+            emulator.read(Address::Absolute(PAGE_SIZE - 3), 6),
+            vec![0, 1, 2, 3, 4, 0]
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        let mut emulator = Emulator::new();
+        assert!(emulator.send_command("explode").is_err());
+        assert!(emulator.send_command("poke 0x10 0x123").is_err());
+    }
+}
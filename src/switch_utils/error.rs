@@ -0,0 +1,163 @@
+use std::{error::Error, fmt, io};
+
+use rusb::Direction;
+
+use super::Address;
+
+/// Everything that can go wrong while talking to a sysbot
+#[derive(Debug)]
+pub enum SwitchError {
+    /// No sysbot device could be found
+    DeviceNotFound,
+    /// The device was found but we're not allowed to open it (udev rules?)
+    PermissionDenied,
+    /// The device has no bulk endpoint in the given direction
+    EndpointMissing(Direction),
+    /// The connection to the sysbot dropped mid-session
+    Disconnected,
+    /// The sysbot didn't answer in time
+    Timeout,
+    /// The transfer ended before all expected bytes arrived
+    ShortRead { expected: usize, received: usize },
+    /// The sysbot announced a different payload size than requested
+    SizeMismatch { expected: usize, announced: usize },
+    /// The sysbot sent something we don't understand, or rejected a command
+    Protocol(String),
+    /// A command argument was rejected before it was sent
+    InvalidArgument(String),
+    /// Offsetting an address ran past the end of the 64-bit address space
+    AddressOverflow { address: Address, delta: u64 },
+    /// Following a pointer chain hit a null pointer after `depth` reads
+    NullPointer { chain: String, depth: usize },
+    /// A different game is running than the one we meant to modify
+    WrongTitle { expected: u64, running: u64 },
+    /// The running release of the game isn't in the offset table
+    UnsupportedBuild { build_id: u64 },
+    /// A write still read back differently after every attempt at it
+    WriteMismatch {
+        target: String,
+        attempts: u32,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// Any other USB failure
+    Usb(rusb::Error),
+    /// Any other I/O failure
+    Io(io::Error),
+}
+
+impl SwitchError {
+    /// Whether the error means the sysbot went away, so reconnecting might help
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            SwitchError::DeviceNotFound | SwitchError::Disconnected => true,
+            SwitchError::Usb(e) => matches!(e, rusb::Error::Io | rusb::Error::Pipe),
+            SwitchError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for SwitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwitchError::DeviceNotFound => write!(f, "Couldn't find switch device"),
+            SwitchError::PermissionDenied => {
+                write!(f, "Permission denied while opening switch device")
+            }
+            SwitchError::EndpointMissing(direction) => {
+                write!(f, "Could not get {:?} endpoint", direction)
+            }
+            SwitchError::Disconnected => write!(f, "Lost connection to switch"),
+            SwitchError::Timeout => write!(f, "Timed out waiting for switch"),
+            SwitchError::ShortRead { expected, received } => write!(
+                f,
+                "Short read from switch: received {} of {} bytes",
+                received, expected
+            ),
+            SwitchError::SizeMismatch { expected, announced } => write!(
+                f,
+                "Switch announced {} bytes, expected {}",
+                announced, expected
+            ),
+            SwitchError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            SwitchError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            SwitchError::AddressOverflow { address, delta } => {
+                write!(f, "Address {} + 0x{:x} overflows", address, delta)
+            }
+            SwitchError::NullPointer { chain, depth } => {
+                write!(f, "Pointer {} is null after {} reads", chain, depth + 1)
+            }
+            SwitchError::WrongTitle { expected, running } => write!(
+                f,
+                "Expected title {:016X} to be running, found {:016X}",
+                expected, running
+            ),
+            SwitchError::UnsupportedBuild { build_id } => write!(
+                f,
+                "No offsets known for game build {:016X}, so it can't be edited safely",
+                build_id
+            ),
+            SwitchError::WriteMismatch {
+                target,
+                attempts,
+                expected,
+                actual,
+            } => {
+                let differing: Vec<usize> = (0..expected.len())
+                    .filter(|&i| expected.get(i) != actual.get(i))
+                    .collect();
+                write!(
+                    f,
+                    "Write to {} didn't take after {} attempts: {} of {} bytes differ, first at +0x{:x}",
+                    target,
+                    attempts,
+                    differing.len(),
+                    expected.len(),
+                    differing.first().copied().unwrap_or(0)
+                )
+            }
+            SwitchError::Usb(e) => write!(f, "USB error: {}", e),
+            SwitchError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for SwitchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SwitchError::Usb(e) => Some(e),
+            SwitchError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusb::Error> for SwitchError {
+    fn from(e: rusb::Error) -> Self {
+        match e {
+            rusb::Error::Timeout => SwitchError::Timeout,
+            rusb::Error::Access => SwitchError::PermissionDenied,
+            rusb::Error::NoDevice => SwitchError::Disconnected,
+            rusb::Error::NotFound => SwitchError::DeviceNotFound,
+            e => SwitchError::Usb(e),
+        }
+    }
+}
+
+impl From<io::Error> for SwitchError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => SwitchError::Timeout,
+            io::ErrorKind::PermissionDenied => SwitchError::PermissionDenied,
+            _ => SwitchError::Io(e),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! The hex text sysbot speaks, also used in the editor's own files.
+
+/// `bytes` as lowercase hex digits, two per byte
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex digits back into bytes, or `None` if `text` isn't an even number of
+/// hex digits
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(encode(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert_eq!(decode("00AB7f"), Some(vec![0x00, 0xab, 0x7f]));
+        assert_eq!(decode(""), Some(vec![]));
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("é0"), None);
+    }
+}
@@ -0,0 +1,119 @@
+use rusb::{Context, Device, Hotplug, HotplugBuilder, UsbContext};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{usb, SwitchError, UsbPort};
+
+/// How often the watcher thread wakes up, both to check for libusb events and,
+/// without hotplug support, to rescan the bus
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A USB sysbot device appearing or disappearing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Arrived(UsbPort),
+    Left(UsbPort),
+}
+
+/// Watches the USB bus for sysbot devices coming and going.
+///
+/// Uses libusb hotplug notifications where the platform has them and rescans
+/// the bus every half second otherwise. Devices already plugged in are
+/// reported as arrivals when the watcher starts.
+pub struct UsbWatcher {
+    events: Receiver<DeviceEvent>,
+    stop: Arc<AtomicBool>,
+}
+
+impl UsbWatcher {
+    /// Start watching on a background thread
+    pub fn start() -> Result<Self, SwitchError> {
+        let ctx = Context::new()?;
+        let (sender, events) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = stop.clone();
+        if rusb::has_hotplug() {
+            let registration = HotplugBuilder::new()
+                .vendor_id(usb::SYSBOT_VENDOR_ID)
+                .product_id(usb::SYSBOT_PRODUCT_ID)
+                .enumerate(true)
+                .register(&ctx, Box::new(Notifier { sender }))?;
+            thread::spawn(move || {
+                let _registration = registration;
+                while !thread_stop.load(Ordering::Relaxed) {
+                    if ctx.handle_events(Some(POLL_INTERVAL)).is_err() {
+                        break;
+                    }
+                }
+            });
+        } else {
+            thread::spawn(move || poll(ctx, sender, thread_stop));
+        }
+
+        Ok(UsbWatcher { events, stop })
+    }
+
+    /// Every event since the last call, without blocking
+    pub fn events(&self) -> impl Iterator<Item = DeviceEvent> + '_ {
+        self.events.try_iter()
+    }
+}
+
+impl Drop for UsbWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Notifier {
+    sender: Sender<DeviceEvent>,
+}
+
+impl Hotplug<Context> for Notifier {
+    fn device_arrived(&mut self, device: Device<Context>) {
+        if let Ok(port) = usb::device_port(&device) {
+            let _ = self.sender.send(DeviceEvent::Arrived(port));
+        }
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        if let Ok(port) = usb::device_port(&device) {
+            let _ = self.sender.send(DeviceEvent::Left(port));
+        }
+    }
+}
+
+/// Fallback for platforms without hotplug: rescan the bus and report the
+/// difference from the last scan
+fn poll(ctx: Context, sender: Sender<DeviceEvent>, stop: Arc<AtomicBool>) {
+    let mut known: HashSet<UsbPort> = HashSet::new();
+    while !stop.load(Ordering::Relaxed) {
+        if let Ok(devices) = ctx.devices() {
+            let present: HashSet<UsbPort> = devices
+                .iter()
+                .filter(|device| device.device_descriptor().is_ok_and(|d| usb::is_sysbot(&d)))
+                .filter_map(|device| usb::device_port(&device).ok())
+                .collect();
+            let events = present
+                .difference(&known)
+                .map(|port| DeviceEvent::Arrived(port.clone()))
+                .chain(known.difference(&present).map(|port| DeviceEvent::Left(port.clone())));
+            for event in events {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+            known = present;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,221 @@
+use std::{fmt, str::FromStr};
+
+use super::SwitchError;
+
+/// Touch screen width in pixels
+pub const SCREEN_WIDTH: u32 = 1280;
+/// Touch screen height in pixels
+pub const SCREEN_HEIGHT: u32 = 720;
+
+/// Controller buttons understood by sysbot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    A,
+    B,
+    X,
+    Y,
+    LStick,
+    RStick,
+    L,
+    R,
+    ZL,
+    ZR,
+    Plus,
+    Minus,
+    DLeft,
+    DUp,
+    DRight,
+    DDown,
+    Home,
+    Capture,
+}
+
+impl Button {
+    pub const ALL: [Button; 18] = [
+        Button::A,
+        Button::B,
+        Button::X,
+        Button::Y,
+        Button::LStick,
+        Button::RStick,
+        Button::L,
+        Button::R,
+        Button::ZL,
+        Button::ZR,
+        Button::Plus,
+        Button::Minus,
+        Button::DLeft,
+        Button::DUp,
+        Button::DRight,
+        Button::DDown,
+        Button::Home,
+        Button::Capture,
+    ];
+
+    /// Name of the button in sysbot commands
+    pub fn sysbot_name(&self) -> &'static str {
+        match self {
+            Button::A => "A",
+            Button::B => "B",
+            Button::X => "X",
+            Button::Y => "Y",
+            Button::LStick => "LSTICK",
+            Button::RStick => "RSTICK",
+            Button::L => "L",
+            Button::R => "R",
+            Button::ZL => "ZL",
+            Button::ZR => "ZR",
+            Button::Plus => "PLUS",
+            Button::Minus => "MINUS",
+            Button::DLeft => "DLEFT",
+            Button::DUp => "DUP",
+            Button::DRight => "DRIGHT",
+            Button::DDown => "DDOWN",
+            Button::Home => "HOME",
+            Button::Capture => "CAPTURE",
+        }
+    }
+}
+
+impl fmt::Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.sysbot_name())
+    }
+}
+
+impl FromStr for Button {
+    type Err = SwitchError;
+
+    /// Parse a sysbot button name, case-insensitively
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Button::ALL
+            .iter()
+            .find(|b| b.sysbot_name().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| SwitchError::InvalidArgument(format!("Unknown button {:?}", s)))
+    }
+}
+
+/// Analog sticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+impl Stick {
+    /// Name of the stick in sysbot commands
+    pub fn sysbot_name(&self) -> &'static str {
+        match self {
+            Stick::Left => "LEFT",
+            Stick::Right => "RIGHT",
+        }
+    }
+}
+
+impl fmt::Display for Stick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.sysbot_name())
+    }
+}
+
+impl FromStr for Stick {
+    type Err = SwitchError;
+
+    /// Parse `LEFT`/`RIGHT` (or just `L`/`R`), case-insensitively
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "L" | "LEFT" => Ok(Stick::Left),
+            "R" | "RIGHT" => Ok(Stick::Right),
+            _ => Err(SwitchError::InvalidArgument(format!("Unknown stick {:?}", s))),
+        }
+    }
+}
+
+/// Check a stick axis value fits the signed 16-bit range sysbot expects
+pub fn validate_stick_axis(value: i32) -> Result<i16, SwitchError> {
+    i16::try_from(value).map_err(|_| {
+        SwitchError::InvalidArgument(format!(
+            "Stick axis {} out of range {}..={}",
+            value,
+            i16::MIN,
+            i16::MAX
+        ))
+    })
+}
+
+/// Check a touch position lies on the screen
+pub fn validate_touch(x: u32, y: u32) -> Result<(), SwitchError> {
+    if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+        return Err(SwitchError::InvalidArgument(format!(
+            "Touch position ({}, {}) is off the {}x{} screen",
+            x, y, SCREEN_WIDTH, SCREEN_HEIGHT
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, Switch};
+
+    #[test]
+    fn button_names_round_trip() {
+        for button in Button::ALL {
+            assert_eq!(button.to_string().parse::<Button>().unwrap(), button);
+        }
+        assert_eq!("zl".parse::<Button>().unwrap(), Button::ZL);
+        assert!("START".parse::<Button>().is_err());
+    }
+
+    #[test]
+    fn stick_names() {
+        assert_eq!("l".parse::<Stick>().unwrap(), Stick::Left);
+        assert_eq!("RIGHT".parse::<Stick>().unwrap(), Stick::Right);
+        assert!("middle".parse::<Stick>().is_err());
+    }
+
+    #[test]
+    fn argument_validation() {
+        assert_eq!(validate_stick_axis(-32768).unwrap(), i16::MIN);
+        assert!(validate_stick_axis(32768).is_err());
+        assert!(validate_touch(1279, 719).is_ok());
+        assert!(validate_touch(1280, 0).is_err());
+        assert!(validate_touch(0, 720).is_err());
+    }
+
+    #[test]
+    fn switch_controller_input() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        switch.click(Button::A).unwrap();
+        switch.press(Button::ZL).unwrap();
+        switch.release(Button::ZL).unwrap();
+        switch.set_stick(Stick::Left, -32768, 32767).unwrap();
+        switch.touch(640, 360).unwrap();
+        switch.detach_controller().unwrap();
+
+        assert!(matches!(
+            switch.set_stick(Stick::Right, 0, 40000),
+            Err(SwitchError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            switch.touch(1280, 0),
+            Err(SwitchError::InvalidArgument(_))
+        ));
+
+        assert_eq!(
+            emulator.commands(),
+            vec![
+                "click A",
+                "press ZL",
+                "release ZL",
+                "setStick LEFT -32768 32767",
+                "touch 640 360",
+                "detachController"
+            ]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::{fmt, str::FromStr};
+
+use super::{Address, SwitchError};
+
+/// A pointer chain in the bracket notation the community publishes, e.g.
+/// `[[main+0x3C95A50]+0x10]+0x8`.
+///
+/// `base` is read as a 64-bit pointer, then for each entry in `offsets` the
+/// offset is added to the pointer read so far; every offset except the last is
+/// followed by another read.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PointerChain {
+    pub base: Address,
+    pub offsets: Vec<i64>,
+}
+
+impl PointerChain {
+    /// How many pointers have to be read to resolve the chain
+    pub fn depth(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Arguments for sysbot's `pointer*` commands, which only start from main:
+    /// the main offset, the jumps, then the final offset
+    pub fn sysbot_jumps(&self) -> Option<String> {
+        match self.base {
+            Address::Main(base) if !self.offsets.is_empty() => Some(
+                std::iter::once(format_signed(base as i64))
+                    .chain(self.offsets.iter().map(|o| format_signed(*o)))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PointerChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", "[".repeat(self.offsets.len()))?;
+        match self.base {
+            Address::Heap(a) => write!(f, "heap+0x{:X}", a)?,
+            Address::Main(a) => write!(f, "main+0x{:X}", a)?,
+            Address::Absolute(a) => write!(f, "0x{:X}", a)?,
+        }
+        for offset in &self.offsets {
+            write!(f, "]")?;
+            if *offset != 0 {
+                write!(f, "{}", format_offset(*offset))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PointerChain {
+    type Err = SwitchError;
+
+    /// Parse `[[main+X]+Y]+Z`, with `heap+X` or a bare absolute `X` also
+    /// accepted as the innermost term and `-` allowed for any offset
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| SwitchError::InvalidArgument(format!("Pointer {:?}: {}", s, why));
+        let text: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+
+        let depth = text.chars().take_while(|c| *c == '[').count();
+        let rest = &text[depth..];
+        let base_end = rest.find(']').unwrap_or(rest.len());
+        let base = parse_base(&rest[..base_end]).ok_or_else(|| invalid("bad base"))?;
+
+        let mut offsets = Vec::with_capacity(depth);
+        let mut rest = &rest[base_end..];
+        while let Some(after) = rest.strip_prefix(']') {
+            let end = after.find(']').unwrap_or(after.len());
+            let offset = match &after[..end] {
+                "" => 0,
+                term => parse_offset(term).ok_or_else(|| invalid("bad offset"))?,
+            };
+            offsets.push(offset);
+            rest = &after[end..];
+        }
+        if !rest.is_empty() || offsets.len() != depth {
+            return Err(invalid("unbalanced brackets"));
+        }
+
+        Ok(PointerChain { base, offsets })
+    }
+}
+
+fn parse_base(term: &str) -> Option<Address> {
+    let lower = term.to_ascii_lowercase();
+    if let Some(offset) = lower.strip_prefix("main+") {
+        Some(Address::Main(parse_hex(offset)?))
+    } else if let Some(offset) = lower.strip_prefix("heap+") {
+        Some(Address::Heap(parse_hex(offset)?))
+    } else {
+        Some(Address::Absolute(parse_hex(&lower)?))
+    }
+}
+
+fn parse_offset(term: &str) -> Option<i64> {
+    if let Some(value) = term.strip_prefix('+') {
+        i64::try_from(parse_hex(value)?).ok()
+    } else if let Some(value) = term.strip_prefix('-') {
+        i64::try_from(parse_hex(value)?).ok().map(|v| -v)
+    } else {
+        None
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+fn format_offset(offset: i64) -> String {
+    if offset < 0 {
+        format!("-0x{:X}", offset.unsigned_abs())
+    } else {
+        format!("+0x{:X}", offset)
+    }
+}
+
+fn format_signed(value: i64) -> String {
+    if value < 0 {
+        format!("-0x{:x}", value.unsigned_abs())
+    } else {
+        format!("0x{:x}", value)
+    }
+}
+
+/// Add a signed offset to a resolved pointer
+pub(super) fn apply_offset(pointer: u64, offset: i64) -> Result<u64, SwitchError> {
+    pointer
+        .checked_add_signed(offset)
+        .ok_or(SwitchError::AddressOverflow {
+            address: Address::Absolute(pointer),
+            delta: offset as u64,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, Switch, EMULATOR_HEAP_BASE};
+
+    #[test]
+    fn parse_community_notation() {
+        let chain: PointerChain = "[[main+3C95A50]+10]+8".parse().unwrap();
+        assert_eq!(
+            chain,
+            PointerChain {
+                base: Address::Main(0x3C95A50),
+                offsets: vec![0x10, 0x8]
+            }
+        );
+        assert_eq!(chain.to_string(), "[[main+0x3C95A50]+0x10]+0x8");
+        assert_eq!(chain.sysbot_jumps().unwrap(), "0x3c95a50 0x10 0x8");
+    }
+
+    #[test]
+    fn parse_variants() {
+        let chain: PointerChain = "[ [ HEAP+0x40 ] - 0x20 ]".parse().unwrap();
+        assert_eq!(chain.base, Address::Heap(0x40));
+        assert_eq!(chain.offsets, vec![-0x20, 0]);
+        assert_eq!(chain.to_string(), "[[heap+0x40]-0x20]");
+        assert_eq!(chain.sysbot_jumps(), None);
+
+        let chain: PointerChain = "main+0x100".parse().unwrap();
+        assert_eq!(chain.depth(), 0);
+        assert_eq!(chain.sysbot_jumps(), None);
+
+        let chain: PointerChain = "[0x8004000000]+0x8".parse().unwrap();
+        assert_eq!(chain.base, Address::Absolute(0x80_0400_0000));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in ["[main+10", "main+10]", "[main+xyz]", "[main+10]*2", "[main+10]+", ""] {
+            assert!(text.parse::<PointerChain>().is_err(), "{:?}", text);
+        }
+    }
+
+    /// `[[main+0x100]+0x20]+0x8` pointing into the heap at 0x5000
+    fn pointer_fixture() -> (Emulator, PointerChain) {
+        let emulator = Emulator::new();
+        emulator.write(Address::Main(0x100), &(EMULATOR_HEAP_BASE + 0x1000).to_le_bytes());
+        emulator.write(Address::Heap(0x1020), &(EMULATOR_HEAP_BASE + 0x4ff8).to_le_bytes());
+        emulator.write(Address::Heap(0x5000), &[0xaa, 0xbb]);
+        (emulator, "[[main+0x100]+0x20]+0x8".parse().unwrap())
+    }
+
+    #[test]
+    fn server_and_client_pointers_agree() {
+        let (emulator, chain) = pointer_fixture();
+        let expected = Address::Absolute(EMULATOR_HEAP_BASE + 0x5000);
+
+        for server_pointers in [true, false] {
+            let mut switch = Switch::with_transport(emulator.clone());
+            switch.set_server_pointers(server_pointers);
+
+            assert_eq!(switch.resolve_pointer(&chain).unwrap(), expected);
+
+            let mut buf = [0u8; 2];
+            switch.read_pointer(&chain, &mut buf).unwrap();
+            assert_eq!(buf, [0xaa, 0xbb]);
+
+            switch.write_pointer(&chain, &[0xcc]).unwrap();
+            assert_eq!(emulator.read(Address::Heap(0x5000), 1), vec![0xcc]);
+            emulator.write(Address::Heap(0x5000), &[0xaa]);
+        }
+
+        let commands = emulator.commands();
+        assert!(commands.contains(&"pointerAll 0x100 0x20 0x8".to_string()));
+        assert!(commands.contains(&"pointerPeek 0x2 0x100 0x20 0x8".to_string()));
+        assert!(commands.contains(&"pointerPoke 0xcc 0x100 0x20 0x8".to_string()));
+        assert!(commands.contains(&"peekMain 0x00000100 0x00000008".to_string()));
+    }
+
+    #[test]
+    fn client_pointers_detect_null() {
+        let (emulator, _) = pointer_fixture();
+        let mut switch = Switch::with_transport(emulator);
+        let chain: PointerChain = "[[heap+0x2000]+0x10]".parse().unwrap();
+        assert!(matches!(
+            switch.resolve_pointer(&chain),
+            Err(SwitchError::NullPointer { depth: 0, .. })
+        ));
+    }
+}
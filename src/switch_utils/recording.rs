@@ -0,0 +1,430 @@
+//! Recording sysbot sessions to a log, and answering from a log instead of a
+//! console.
+//!
+//! A recording is plain text, one event per line, each stamped with the
+//! seconds since recording started:
+//!
+//! ```text
+//! # sysbot recording, started at 1700000000
+//!      0.000 > getTitleID
+//!      0.004 < u64 01006f8002326000
+//!      0.010 > peek 0xafb1e6e0 0x8
+//!      0.015 < bytes a216000000000000
+//!      0.020 ! disconnected Switch disconnected
+//!      0.500 ~ reconnect ok
+//! ```
+//!
+//! `>` is a command sent, `<` a reply received, `!` a failed transfer and `~`
+//! a reconnect attempt.
+
+use std::{
+    collections::VecDeque,
+    io::Write,
+    path::Path,
+    str::FromStr,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{hex, SwitchError, Transport};
+
+/// One thing that happened on the link
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Event {
+    Command(String),
+    Bytes(Vec<u8>),
+    U64(u64),
+    Text(String),
+    /// A failed send or receive, and whether it meant the link dropped
+    Error {
+        disconnect: bool,
+        message: String,
+    },
+    Reconnect {
+        ok: bool,
+    },
+}
+
+impl Event {
+    fn error(error: &SwitchError) -> Self {
+        Event::Error {
+            disconnect: error.is_disconnect(),
+            message: error.to_string().replace('\n', " "),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Event::Command(command) => format!("command {:?}", command),
+            Event::Bytes(bytes) => format!("a {} byte reply", bytes.len()),
+            Event::U64(_) => "a number".to_string(),
+            Event::Text(_) => "a text reply".to_string(),
+            Event::Error { message, .. } => format!("error {:?}", message),
+            Event::Reconnect { .. } => "a reconnect".to_string(),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        match self {
+            Event::Command(command) => format!("> {}", command),
+            Event::Bytes(bytes) => format!(
+                "< bytes {}",
+                hex::encode(bytes)
+            ),
+            Event::U64(value) => format!("< u64 {:016x}", value),
+            Event::Text(text) => format!("< text {}", text.replace('\n', " ")),
+            Event::Error {
+                disconnect: true,
+                message,
+            } => format!("! disconnected {}", message),
+            Event::Error {
+                disconnect: false,
+                message,
+            } => format!("! error {}", message),
+            Event::Reconnect { ok } => {
+                format!("~ reconnect {}", if *ok { "ok" } else { "failed" })
+            }
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = SwitchError;
+
+    /// Parse a line without its timestamp
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SwitchError::InvalidArgument(format!("Invalid recorded event {:?}", s));
+        let (kind, rest) = s.split_once(' ').ok_or_else(invalid)?;
+        let (tag, value) = rest.split_once(' ').unwrap_or((rest, ""));
+        Ok(match (kind, tag) {
+            (">", _) => Event::Command(rest.to_string()),
+            ("<", "bytes") => Event::Bytes(hex::decode(value).ok_or_else(invalid)?),
+            ("<", "u64") => Event::U64(u64::from_str_radix(value, 16).map_err(|_| invalid())?),
+            ("<", "text") => Event::Text(value.to_string()),
+            ("!", "disconnected" | "error") => Event::Error {
+                disconnect: tag == "disconnected",
+                message: value.to_string(),
+            },
+            ("~", "reconnect") => Event::Reconnect { ok: value == "ok" },
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// Passes everything through to another transport, logging it as it goes.
+///
+/// Logging is best effort: a failed write to the log never fails the
+/// transfer it describes.
+pub struct Recorder {
+    inner: Box<dyn Transport>,
+    log: Box<dyn Write + Send>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn new(inner: Box<dyn Transport>, mut log: Box<dyn Write + Send>) -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let _ = writeln!(log, "# sysbot recording, started at {}", since_epoch);
+        Recorder {
+            inner,
+            log,
+            started: Instant::now(),
+        }
+    }
+
+    fn log(&mut self, event: &Event) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let _ = writeln!(self.log, "{:>10.3} {}", elapsed, event.to_line());
+        let _ = self.log.flush();
+    }
+
+    /// Log the outcome of a transfer, turning its reply into an event with
+    /// `event`
+    fn log_result<T>(
+        &mut self,
+        result: Result<T, SwitchError>,
+        event: impl FnOnce(&T) -> Event,
+    ) -> Result<T, SwitchError> {
+        match &result {
+            Ok(value) => self.log(&event(value)),
+            Err(e) => self.log(&Event::error(e)),
+        }
+        result
+    }
+}
+
+impl Transport for Recorder {
+    fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+        self.log(&Event::Command(command.to_string()));
+        match self.inner.send_command(command) {
+            Err(e) => {
+                self.log(&Event::error(&e));
+                Err(e)
+            }
+            ok => ok,
+        }
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError> {
+        let result = self.inner.receive_bytes(buf);
+        self.log_result(result, |_| Event::Bytes(buf.to_vec()))
+    }
+
+    fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError> {
+        let result = self.inner.receive_payload();
+        self.log_result(result, |payload| Event::Bytes(payload.clone()))
+    }
+
+    fn receive_u64(&mut self) -> Result<u64, SwitchError> {
+        let result = self.inner.receive_u64();
+        self.log_result(result, |value| Event::U64(*value))
+    }
+
+    fn receive_text(&mut self) -> Result<String, SwitchError> {
+        let result = self.inner.receive_text();
+        self.log_result(result, |text| Event::Text(text.clone()))
+    }
+
+    fn reconnect(&mut self) -> Result<(), SwitchError> {
+        let result = self.inner.reconnect();
+        self.log(&Event::Reconnect { ok: result.is_ok() });
+        result
+    }
+
+    fn is_present(&mut self) -> Option<bool> {
+        self.inner.is_present()
+    }
+}
+
+/// Answers from a recording instead of a console.
+///
+/// The session has to send the same commands in the same order as the
+/// recorded one; anything else is a protocol error naming what the recording
+/// expected. Recorded failures come back as errors, with dropped links as
+/// [`SwitchError::Disconnected`] so reconnects replay too.
+pub struct Replay {
+    events: VecDeque<Event>,
+}
+
+impl Replay {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SwitchError> {
+        super::parse_file(path)
+    }
+
+    /// Whether every recorded event has been replayed
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Take the next event, which must be the kind `expected` describes.
+    /// A recorded error is returned as the error it stood for.
+    fn next<T>(
+        &mut self,
+        expected: &str,
+        accept: impl FnOnce(Event) -> Result<T, Event>,
+    ) -> Result<T, SwitchError> {
+        let event = self.events.pop_front().ok_or_else(|| {
+            SwitchError::Protocol(format!(
+                "Recording ended, but the session wanted {}",
+                expected
+            ))
+        })?;
+        if let Event::Error {
+            disconnect,
+            message,
+        } = event
+        {
+            return Err(replayed_error(disconnect, message));
+        }
+        accept(event).map_err(|event| {
+            SwitchError::Protocol(format!(
+                "Session wanted {} where the recording has {}",
+                expected,
+                event.describe()
+            ))
+        })
+    }
+
+    fn next_bytes(&mut self) -> Result<Vec<u8>, SwitchError> {
+        self.next("a reply", |event| match event {
+            Event::Bytes(bytes) => Ok(bytes),
+            other => Err(other),
+        })
+    }
+}
+
+/// The error a recorded failure stands for
+fn replayed_error(disconnect: bool, message: String) -> SwitchError {
+    if disconnect {
+        SwitchError::Disconnected
+    } else {
+        SwitchError::Protocol(message)
+    }
+}
+
+impl FromStr for Replay {
+    type Err = SwitchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let events = s
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| {
+                // Timestamps are for people reading the log; replay ignores them
+                let event = line.split_once(' ').map_or("", |(_, event)| event);
+                event.trim_start().parse().map_err(|e: SwitchError| {
+                    SwitchError::InvalidArgument(format!("Line {}: {}", i + 1, e))
+                })
+            })
+            .collect::<Result<VecDeque<Event>, SwitchError>>()?;
+        Ok(Replay { events })
+    }
+}
+
+impl Transport for Replay {
+    fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+        let expected = format!("to send {:?}", command);
+        self.next(&expected, |event| match event {
+            Event::Command(recorded) if recorded == command => Ok(()),
+            other => Err(other),
+        })?;
+        // A send that failed is logged straight after the command
+        if matches!(self.events.front(), Some(Event::Error { .. })) {
+            if let Some(Event::Error {
+                disconnect,
+                message,
+            }) = self.events.pop_front()
+            {
+                return Err(replayed_error(disconnect, message));
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError> {
+        let bytes = self.next_bytes()?;
+        if bytes.len() != buf.len() {
+            return Err(SwitchError::SizeMismatch {
+                expected: buf.len(),
+                announced: bytes.len(),
+            });
+        }
+        buf.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError> {
+        self.next_bytes()
+    }
+
+    fn receive_u64(&mut self) -> Result<u64, SwitchError> {
+        self.next("a number", |event| match event {
+            Event::U64(value) => Ok(value),
+            other => Err(other),
+        })
+    }
+
+    fn receive_text(&mut self) -> Result<String, SwitchError> {
+        self.next("a text reply", |event| match event {
+            Event::Text(text) => Ok(text),
+            other => Err(other),
+        })
+    }
+
+    fn reconnect(&mut self) -> Result<(), SwitchError> {
+        if self.next("a reconnect", |event| match event {
+            Event::Reconnect { ok } => Ok(ok),
+            other => Err(other),
+        })? {
+            Ok(())
+        } else {
+            Err(SwitchError::DeviceNotFound)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Address, Emulator, Switch, EMULATOR_TITLE_ID};
+    use std::sync::{Arc, Mutex};
+
+    /// A log the test can read back after handing it to a `Recorder`
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Talk to a console, dropping the link partway through
+    fn session(switch: &mut Switch, drop_link: impl FnOnce()) -> (u64, Vec<u8>, String) {
+        let title_id = switch.title_id().unwrap();
+        switch
+            .write_bytes(Address::Heap(0x100), &[1, 2, 3])
+            .unwrap();
+        drop_link();
+        let mut buf = [0u8; 4];
+        switch
+            .read_bytes(Address::Heap(0x100), &mut buf, 4)
+            .unwrap();
+        (title_id, buf.to_vec(), switch.sysbot_version().unwrap())
+    }
+
+    #[test]
+    fn replays_a_recorded_session() {
+        let emulator = Emulator::new();
+        let log = SharedLog::default();
+        let mut switch = Switch::with_transport(Recorder::new(
+            Box::new(emulator.clone()),
+            Box::new(log.clone()),
+        ));
+        let live = session(&mut switch, || emulator.drop_link());
+        assert_eq!(live.0, EMULATOR_TITLE_ID);
+
+        let recording = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(recording.contains("~ reconnect ok"), "{}", recording);
+        let mut replay: Replay = recording.parse().unwrap();
+        let mut replayed = Switch::with_transport(replay);
+        assert_eq!(session(&mut replayed, || {}), live);
+
+        // A session that strays from the recording is told where
+        replay = recording.parse().unwrap();
+        let mut strayed = Switch::with_transport(replay);
+        let error = strayed.build_id().unwrap_err();
+        assert!(error.to_string().contains("getTitleID"), "{}", error);
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let events = [
+            Event::Command("peek 0x10 0x2".into()),
+            Event::Bytes(vec![0xab, 0x01]),
+            Event::U64(0x0100_6F80_0232_6000),
+            Event::Text("2.4".into()),
+            Event::Error {
+                disconnect: true,
+                message: "Switch disconnected".into(),
+            },
+            Event::Error {
+                disconnect: false,
+                message: "Timed out".into(),
+            },
+            Event::Reconnect { ok: false },
+        ];
+        for event in events {
+            assert_eq!(event.to_line().parse::<Event>().unwrap(), event);
+        }
+        assert!("   0.1 < bytes abc".parse::<Replay>().is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::Path;
+
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    ImageError, ImageFormat, RgbaImage,
+};
+
+use super::SwitchError;
+
+/// A decoded frame grabbed from the Switch
+#[derive(Clone)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels as tightly packed RGBA, row by row
+    pub rgba: Vec<u8>,
+}
+
+impl Screenshot {
+    /// Decode the JPEG that `pixelPeek` returns
+    pub fn from_jpeg(jpeg: &[u8]) -> Result<Self, SwitchError> {
+        let image = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)
+            .map_err(|e| SwitchError::Protocol(format!("Could not decode screenshot: {}", e)))?
+            .to_rgba8();
+        Ok(Screenshot {
+            width: image.width(),
+            height: image.height(),
+            rgba: image.into_raw(),
+        })
+    }
+
+    /// Save the screenshot as a PNG file, failing if the pixel buffer doesn't
+    /// match the dimensions
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        let image = RgbaImage::from_raw(self.width, self.height, self.rgba.clone()).ok_or_else(|| {
+            ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch))
+        })?;
+        image.save_with_format(path, ImageFormat::Png)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::switch_utils::{Emulator, Switch, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+    #[test]
+    fn switch_screenshot() {
+        let emulator = Emulator::new();
+        let mut switch = Switch::with_transport(emulator.clone());
+
+        let screenshot = switch.screenshot().unwrap();
+        assert_eq!((screenshot.width, screenshot.height), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert_eq!(screenshot.rgba.len(), (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize);
+
+        let path = std::env::temp_dir().join(format!("switch-screenshot-{}.png", std::process::id()));
+        screenshot.save_png(&path).unwrap();
+        let saved = image::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((saved.width(), saved.height()), (SCREEN_WIDTH, SCREEN_HEIGHT));
+
+        emulator.set_screen(b"not a jpeg".to_vec());
+        assert!(matches!(switch.screenshot(), Err(SwitchError::Protocol(_))));
+    }
+
+    #[test]
+    fn mismatched_buffers_are_not_saved() {
+        let screenshot = Screenshot {
+            width: 2,
+            height: 2,
+            rgba: vec![0; 4],
+        };
+        let path = std::env::temp_dir().join(format!("switch-mismatched-{}.png", std::process::id()));
+        assert!(matches!(screenshot.save_png(&path), Err(ImageError::Parameter(_))));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use super::{hex, SwitchError, Transport};
+
+/// Port sys-botbase listens on by default
+pub const DEFAULT_PORT: u16 = 6000;
+
+/// How long to wait for a reply, which can take a while for large reads
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for a command to be sent, and for the console to accept
+/// the connection in the first place
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Network sysbot transport (sys-botbase over Wi-Fi).
+///
+/// Commands are newline-terminated text and binary replies come back as a
+/// single line of hex text.
+pub struct TcpTransport {
+    host: String,
+    port: u16,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl TcpTransport {
+    /// Connect to sys-botbase at `host`:`port`, trying each address the host
+    /// resolves to in turn
+    pub fn connect(host: &str, port: u16) -> Result<Self, SwitchError> {
+        let mut last_error = SwitchError::DeviceNotFound;
+        for address in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, WRITE_TIMEOUT) {
+                Ok(stream) => return TcpTransport::with_stream(host, port, stream),
+                Err(e) => last_error = e.into(),
+            }
+        }
+        Err(last_error)
+    }
+
+    fn with_stream(host: &str, port: u16, stream: TcpStream) -> Result<Self, SwitchError> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        Ok(TcpTransport {
+            host: host.to_string(),
+            port,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn receive_line(&mut self) -> Result<String, SwitchError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(SwitchError::Disconnected);
+        }
+        Ok(line.trim_end().to_string())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+        self.writer.write_all(format!("{}\r\n", command).as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError> {
+        let line = self.receive_line()?;
+        decode_hex(&line, buf)
+    }
+
+    fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError> {
+        let line = self.receive_line()?;
+        hex::decode(&line).ok_or_else(|| {
+            SwitchError::Protocol(format!("Invalid hex in sysbot reply: {:?}", line))
+        })
+    }
+
+    fn receive_u64(&mut self) -> Result<u64, SwitchError> {
+        let line = self.receive_line()?;
+        let digits = line.strip_prefix("0x").unwrap_or(&line);
+        u64::from_str_radix(digits, 16)
+            .map_err(|_| SwitchError::Protocol(format!("Invalid number in sysbot reply: {:?}", line)))
+    }
+
+    fn receive_text(&mut self) -> Result<String, SwitchError> {
+        self.receive_line()
+    }
+
+    fn reconnect(&mut self) -> Result<(), SwitchError> {
+        *self = TcpTransport::connect(&self.host, self.port)?;
+        Ok(())
+    }
+}
+
+/// Decode a hex text reply into `buf`, which must match the reply length exactly
+fn decode_hex(text: &str, buf: &mut [u8]) -> Result<(), SwitchError> {
+    let bytes = hex::decode(text).ok_or_else(|| {
+        SwitchError::Protocol(format!("Invalid hex in sysbot reply: {:?}", text))
+    })?;
+    if bytes.len() != buf.len() {
+        return Err(SwitchError::SizeMismatch {
+            expected: buf.len(),
+            announced: bytes.len(),
+        });
+    }
+    buf.copy_from_slice(&bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// Accept a single connection and answer each received line with `reply`
+    fn stand_in<F>(reply: F) -> (u16, thread::JoinHandle<Vec<String>>)
+    where
+        F: Fn(&str) -> Option<String> + Send + 'static,
+    {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                if let Some(answer) = reply(line.trim_end()) {
+                    writer.write_all(answer.as_bytes()).unwrap();
+                }
+                received.push(line.clone());
+                line.clear();
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn refused_connections_fail() {
+        let port = TcpListener::bind(("127.0.0.1", 0)).unwrap().local_addr().unwrap().port();
+        assert!(TcpTransport::connect("127.0.0.1", port).is_err());
+    }
+
+    #[test]
+    fn peek_decodes_hex_reply() {
+        let (port, handle) = stand_in(|line| {
+            line.starts_with("peek").then(|| "DEADBEEF0102\n".to_string())
+        });
+
+        let mut transport = TcpTransport::connect("127.0.0.1", port).unwrap();
+        transport.send_command("peek 0x00000010 0x00000006").unwrap();
+        let mut buf = [0u8; 6];
+        transport.receive_bytes(&mut buf).unwrap();
+        assert_eq!(buf, [0xde, 0xad, 0xbe, 0xef, 0x01, 0x02]);
+
+        drop(transport);
+        assert_eq!(handle.join().unwrap(), vec!["peek 0x00000010 0x00000006\r\n"]);
+    }
+
+    #[test]
+    fn payload_length_comes_from_reply() {
+        let (port, _handle) = stand_in(|_| Some("FFD8FFD9\n".to_string()));
+
+        let mut transport = TcpTransport::connect("127.0.0.1", port).unwrap();
+        transport.send_command("pixelPeek").unwrap();
+        assert_eq!(transport.receive_payload().unwrap(), vec![0xff, 0xd8, 0xff, 0xd9]);
+    }
+
+    #[test]
+    fn numbers_are_hex_text() {
+        let (port, _handle) = stand_in(|_| Some("0x0000008004000000\n".to_string()));
+
+        let mut transport = TcpTransport::connect("127.0.0.1", port).unwrap();
+        transport.send_command("pointerAll 0x10 0x8").unwrap();
+        assert_eq!(transport.receive_u64().unwrap(), 0x80_0400_0000);
+    }
+
+    #[test]
+    fn poke_is_sent_as_a_text_line() {
+        let (port, handle) = stand_in(|_| None);
+
+        let mut transport = TcpTransport::connect("127.0.0.1", port).unwrap();
+        transport.send_command("poke 0x00000010 0x2a").unwrap();
+
+        drop(transport);
+        assert_eq!(handle.join().unwrap(), vec!["poke 0x00000010 0x2a\r\n"]);
+    }
+
+    #[test]
+    fn short_reply_is_an_error() {
+        let (port, _handle) = stand_in(|_| Some("DEAD\n".to_string()));
+
+        let mut transport = TcpTransport::connect("127.0.0.1", port).unwrap();
+        transport.send_command("peek 0x00000010 0x00000004").unwrap();
+        let mut buf = [0u8; 4];
+        assert!(matches!(
+            transport.receive_bytes(&mut buf),
+            Err(SwitchError::SizeMismatch {
+                expected: 4,
+                announced: 2
+            })
+        ));
+    }
+}
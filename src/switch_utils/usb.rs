@@ -0,0 +1,504 @@
+use rusb::{self, Context, Device, DeviceDescriptor, DeviceHandle, Direction, UsbContext};
+use std::{fmt, str::FromStr, time::Duration};
+
+use super::{DeviceEvent, SwitchError, Transport, UsbWatcher};
+
+pub(super) const SYSBOT_VENDOR_ID: u16 = 0x057E;
+pub(super) const SYSBOT_PRODUCT_ID: u16 = 0x3000;
+
+/// Where a USB device is plugged in: its bus and the chain of hub ports
+/// leading to it. Unlike the device address, this stays the same when the
+/// console is unplugged and plugged back into the same port.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UsbPort {
+    pub bus: u8,
+    pub ports: Vec<u8>,
+}
+
+impl fmt::Display for UsbPort {
+    /// Formatted like Linux sysfs names, e.g. `1-2.4`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}-{}", self.bus, ports.join("."))
+    }
+}
+
+impl FromStr for UsbPort {
+    type Err = SwitchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SwitchError::InvalidArgument(format!("Invalid USB port {:?}", s));
+        let (bus, ports) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(UsbPort {
+            bus: bus.parse().map_err(|_| invalid())?,
+            ports: ports
+                .split('.')
+                .map(|p| p.parse().map_err(|_| invalid()))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// A connected USB sysbot device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDeviceInfo {
+    pub port: UsbPort,
+    /// USB device address on its bus; changes on every reconnect
+    pub address: u8,
+    /// Serial number, when the device could be opened to read it
+    pub serial: Option<String>,
+}
+
+impl fmt::Display for UsbDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "USB {}", self.port)?;
+        if let Some(serial) = &self.serial {
+            write!(f, " ({})", serial)?;
+        }
+        Ok(())
+    }
+}
+
+/// List every USB sysbot device attached to this machine
+pub fn list_devices() -> Result<Vec<UsbDeviceInfo>, SwitchError> {
+    let ctx = rusb::Context::new()?;
+    let mut devices = Vec::new();
+    for device in ctx.devices()?.iter() {
+        let Ok(descriptor) = device.device_descriptor() else {
+            continue;
+        };
+        if !is_sysbot(&descriptor) {
+            continue;
+        }
+        let serial = device
+            .open()
+            .ok()
+            .and_then(|handle| handle.read_serial_number_string_ascii(&descriptor).ok());
+        devices.push(UsbDeviceInfo {
+            port: device_port(&device)?,
+            address: device.address(),
+            serial,
+        });
+    }
+    Ok(devices)
+}
+
+pub(super) fn is_sysbot(descriptor: &DeviceDescriptor) -> bool {
+    descriptor.vendor_id() == SYSBOT_VENDOR_ID && descriptor.product_id() == SYSBOT_PRODUCT_ID
+}
+
+pub(super) fn device_port(device: &Device<Context>) -> Result<UsbPort, SwitchError> {
+    Ok(UsbPort {
+        bus: device.bus_number(),
+        ports: device.port_numbers()?,
+    })
+}
+
+/// USB sysbot transport (usb-botbase)
+pub struct UsbTransport {
+    /// Where the device was found, so a reconnect opens the same console
+    port: UsbPort,
+    link: Link,
+    /// Hotplug watcher, if one could be started, and whether it last saw our
+    /// device plugged in
+    watcher: Option<UsbWatcher>,
+    present: bool,
+}
+
+/// An open device handle with its interfaces claimed, and the endpoints
+/// resolved for it. Dropping it releases the interfaces again.
+struct Link {
+    read_endpoint: Endpoint,
+    write_endpoint: Endpoint,
+    switch_handle: DeviceHandle<Context>,
+    /// Interfaces we claimed
+    claimed: Vec<u8>,
+    /// Interfaces whose kernel driver we detached, to reattach on drop
+    detached: Vec<u8>,
+}
+
+impl UsbTransport {
+    /// Open the first USB sysbot device found
+    pub fn new() -> Result<Self, SwitchError> {
+        UsbTransport::open(None)
+    }
+
+    /// Open the USB sysbot device plugged into `port`, or the first one found
+    pub fn open(port: Option<&UsbPort>) -> Result<Self, SwitchError> {
+        let (port, link) = Link::open(port)?;
+        Ok(UsbTransport {
+            port,
+            link,
+            watcher: UsbWatcher::start().ok(),
+            present: true,
+        })
+    }
+
+    /// The port the console is plugged into
+    pub fn port(&self) -> &UsbPort {
+        &self.port
+    }
+}
+
+impl Link {
+    fn open(port: Option<&UsbPort>) -> Result<(UsbPort, Self), SwitchError> {
+        let ctx = rusb::Context::new()?;
+
+        let switch_device = ctx
+            .devices()?
+            .iter()
+            .find(|device| {
+                device.device_descriptor().is_ok_and(|d| is_sysbot(&d))
+                    && port.is_none_or(|port| device_port(device).is_ok_and(|p| &p == port))
+            })
+            .ok_or(SwitchError::DeviceNotFound)?;
+        let switch_descriptor = switch_device.device_descriptor()?;
+        let switch_handle = switch_device.open()?;
+
+        let read_endpoint = get_switch_enpoint(&switch_descriptor, &switch_device, Direction::In)
+            .ok_or(SwitchError::EndpointMissing(Direction::In))?;
+        let write_endpoint = get_switch_enpoint(&switch_descriptor, &switch_device, Direction::Out)
+            .ok_or(SwitchError::EndpointMissing(Direction::Out))?;
+
+        let mut link = Link {
+            read_endpoint,
+            write_endpoint,
+            switch_handle,
+            claimed: Vec::new(),
+            detached: Vec::new(),
+        };
+        link.claim()?;
+        Ok((device_port(&switch_device)?, link))
+    }
+
+    /// Select the endpoints' configuration and claim their interfaces, taking
+    /// them from the kernel driver if one is bound
+    fn claim(&mut self) -> Result<(), SwitchError> {
+        let mut ifaces = vec![self.read_endpoint.iface];
+        if self.write_endpoint.iface != self.read_endpoint.iface {
+            ifaces.push(self.write_endpoint.iface);
+        }
+
+        for &iface in &ifaces {
+            // Not every platform can tell; assume no driver if it can't
+            if self.switch_handle.kernel_driver_active(iface).unwrap_or(false) {
+                self.switch_handle.detach_kernel_driver(iface)?;
+                self.detached.push(iface);
+            }
+        }
+
+        let config = self.read_endpoint.config;
+        if self.switch_handle.active_configuration()? != config {
+            self.switch_handle.set_active_configuration(config)?;
+        }
+
+        for endpoint in [&self.read_endpoint, &self.write_endpoint] {
+            if !self.claimed.contains(&endpoint.iface) {
+                self.switch_handle.claim_interface(endpoint.iface)?;
+                self.claimed.push(endpoint.iface);
+            }
+            self.switch_handle
+                .set_alternate_setting(endpoint.iface, endpoint.setting)?;
+        }
+        Ok(())
+    }
+
+    fn bulk_read(&mut self) -> impl FnMut(&mut [u8], Duration) -> rusb::Result<usize> + '_ {
+        let address = self.read_endpoint.address;
+        move |buf, timeout| self.switch_handle.read_bulk(address, buf, timeout)
+    }
+
+    /// Release our interfaces and hand them back to the kernel driver
+    fn release(&mut self) {
+        // Nothing useful to do about failures here; the device may already be gone
+        for iface in self.claimed.drain(..) {
+            let _ = self.switch_handle.release_interface(iface);
+        }
+        for iface in self.detached.drain(..) {
+            let _ = self.switch_handle.attach_kernel_driver(iface);
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl Transport for UsbTransport {
+    fn send_command(&mut self, command: &str) -> Result<(), SwitchError> {
+        let link = &mut self.link;
+        send_command(&mut link.switch_handle, &link.write_endpoint, command)
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> Result<(), SwitchError> {
+        receive_bytes(&mut self.link.bulk_read(), buf)
+    }
+
+    fn receive_payload(&mut self) -> Result<Vec<u8>, SwitchError> {
+        receive_payload(&mut self.link.bulk_read())
+    }
+
+    fn receive_u64(&mut self) -> Result<u64, SwitchError> {
+        let mut buf = [0u8; 8];
+        self.receive_bytes(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn receive_text(&mut self) -> Result<String, SwitchError> {
+        let payload = self.receive_payload()?;
+        let text = String::from_utf8(payload)
+            .map_err(|_| SwitchError::Protocol("Sysbot sent text that isn't UTF-8".into()))?;
+        Ok(text.trim_end().to_string())
+    }
+
+    fn reconnect(&mut self) -> Result<(), SwitchError> {
+        // The interfaces have to be free before a new handle can claim them
+        self.link.release();
+        let (_, link) = Link::open(Some(&self.port))?;
+        self.link = link;
+        self.present = true;
+        Ok(())
+    }
+
+    fn is_present(&mut self) -> Option<bool> {
+        let watcher = self.watcher.as_ref()?;
+        for event in watcher.events() {
+            match event {
+                DeviceEvent::Arrived(port) if port == self.port => self.present = true,
+                DeviceEvent::Left(port) if port == self.port => self.present = false,
+                _ => {}
+            }
+        }
+        Some(self.present)
+    }
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    config: u8,
+    iface: u8,
+    setting: u8,
+    address: u8,
+}
+
+fn get_switch_enpoint(
+    switch_descriptor: &DeviceDescriptor,
+    switch_device: &Device<Context>,
+    direction: Direction,
+) -> Option<Endpoint> {
+    for i in 0..switch_descriptor.num_configurations() {
+        let config_desc = match switch_device.config_descriptor(i) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        for interface in config_desc.interfaces() {
+            for interface_desc in interface.descriptors() {
+                for endpoint_desc in interface_desc.endpoint_descriptors() {
+                    if endpoint_desc.direction() == direction {
+                        return Some(Endpoint {
+                            config: config_desc.number(),
+                            iface: interface.number(),
+                            setting: interface_desc.setting_number(),
+                            address: endpoint_desc.address(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+fn send_command(
+    switch_handle: &mut DeviceHandle<Context>,
+    write_endpoint: &Endpoint,
+    command: &str,
+) -> Result<(), SwitchError> {
+    let bytes_to_send = ((command.len() + 2) as u32).to_le_bytes();
+    switch_handle.write_bulk(
+        write_endpoint.address,
+        &bytes_to_send,
+        Duration::from_secs(5),
+    )?;
+    switch_handle.write_bulk(
+        write_endpoint.address,
+        format!("{}\r\n", command).as_bytes(),
+        Duration::from_secs(5),
+    )?;
+    Ok(())
+}
+
+/// A bulk IN endpoint: fills the buffer from one transfer and returns how
+/// many bytes arrived
+type BulkRead<'a> = dyn FnMut(&mut [u8], Duration) -> rusb::Result<usize> + 'a;
+
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const PAYLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for more of a reply that is being thrown away
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+/// Scratch size for draining; a multiple of every bulk max packet size
+const DRAIN_CHUNK_SIZE: usize = 0x1000;
+/// Largest reply of unknown size we'll accept. The biggest is a `pixelPeek`
+/// JPEG, which usb-botbase caps at 0x7D000 bytes; a header announcing more
+/// than this means the stream is out of step.
+const MAX_PAYLOAD_SIZE: usize = 0x80000;
+
+/// Fill `buf` from as many bulk transfers as it takes
+fn read_exact(read: &mut BulkRead, buf: &mut [u8], timeout: Duration) -> Result<(), SwitchError> {
+    let mut received = 0;
+    while received < buf.len() {
+        match read(&mut buf[received..], timeout) {
+            Ok(0) => break,
+            Ok(n) => received += n,
+            Err(rusb::Error::Timeout) if received > 0 => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if received < buf.len() {
+        return Err(SwitchError::ShortRead {
+            expected: buf.len(),
+            received,
+        });
+    }
+    Ok(())
+}
+
+/// Throw away up to `length` bytes of a reply we can't use, so the next reply
+/// starts at its size header
+fn drain(read: &mut BulkRead, mut length: usize) {
+    let mut scratch = [0u8; DRAIN_CHUNK_SIZE];
+    while length > 0 {
+        let chunk = length.min(scratch.len());
+        match read(&mut scratch[..chunk], DRAIN_TIMEOUT) {
+            Ok(n) if n > 0 => length = length.saturating_sub(n),
+            _ => break,
+        }
+    }
+}
+
+/// Read an announced payload into `buf`. If the reply is cut short, whatever
+/// else arrives of it is discarded.
+fn receive_into(read: &mut BulkRead, buf: &mut [u8]) -> Result<(), SwitchError> {
+    let result = read_exact(read, buf, PAYLOAD_TIMEOUT);
+    if let Err(SwitchError::ShortRead { expected, received }) = result {
+        drain(read, expected - received);
+    }
+    result
+}
+
+fn receive_size(read: &mut BulkRead) -> Result<usize, SwitchError> {
+    let mut size_recv: [u8; 4] = [0; 4];
+    read_exact(read, &mut size_recv, HEADER_TIMEOUT)?;
+    Ok(u32::from_le_bytes(size_recv) as usize)
+}
+
+/// Receive a reply that must be exactly `buf.len()` bytes long. A reply of
+/// any other size is drained and reported as [`SwitchError::SizeMismatch`].
+fn receive_bytes(read: &mut BulkRead, buf: &mut [u8]) -> Result<(), SwitchError> {
+    let announced = receive_size(read)?;
+    if announced != buf.len() {
+        drain(read, announced);
+        return Err(SwitchError::SizeMismatch {
+            expected: buf.len(),
+            announced,
+        });
+    }
+    receive_into(read, buf)
+}
+
+/// Receive a reply of whatever size it announces, up to [`MAX_PAYLOAD_SIZE`].
+/// A larger reply is drained and reported as [`SwitchError::SizeMismatch`].
+fn receive_payload(read: &mut BulkRead) -> Result<Vec<u8>, SwitchError> {
+    let announced = receive_size(read)?;
+    if announced > MAX_PAYLOAD_SIZE {
+        drain(read, announced);
+        return Err(SwitchError::SizeMismatch {
+            expected: MAX_PAYLOAD_SIZE,
+            announced,
+        });
+    }
+    let mut buf = vec![0; announced];
+    receive_into(read, &mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// A bulk endpoint that delivers `transfers` one at a time, then times out
+    fn endpoint(transfers: &[&[u8]]) -> impl FnMut(&mut [u8], Duration) -> rusb::Result<usize> {
+        let mut transfers: VecDeque<Vec<u8>> = transfers.iter().map(|t| t.to_vec()).collect();
+        move |buf, _| {
+            let transfer = transfers.pop_front().ok_or(rusb::Error::Timeout)?;
+            if transfer.len() > buf.len() {
+                return Err(rusb::Error::Overflow);
+            }
+            buf[..transfer.len()].copy_from_slice(&transfer);
+            Ok(transfer.len())
+        }
+    }
+
+    #[test]
+    fn payload_split_across_transfers() {
+        let mut read = endpoint(&[&[6, 0, 0, 0], &[1, 2], &[3, 4, 5], &[6]]);
+        let mut buf = [0u8; 6];
+        receive_bytes(&mut read, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn size_mismatch_drains_the_reply() {
+        let mut read = endpoint(&[&[3, 0, 0, 0], &[9, 9, 9], &[2, 0, 0, 0], &[1, 2]]);
+        let mut buf = [0u8; 2];
+        assert!(matches!(
+            receive_bytes(&mut read, &mut buf),
+            Err(SwitchError::SizeMismatch {
+                expected: 2,
+                announced: 3
+            })
+        ));
+        receive_bytes(&mut read, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+    }
+
+    #[test]
+    fn truncated_reply_is_a_short_read() {
+        let mut read = endpoint(&[&[8, 0, 0, 0], &[1, 2, 3]]);
+        assert!(matches!(
+            receive_payload(&mut read),
+            Err(SwitchError::ShortRead {
+                expected: 8,
+                received: 3
+            })
+        ));
+
+        let mut read = endpoint(&[&[4, 0]]);
+        assert!(matches!(
+            receive_payload(&mut read),
+            Err(SwitchError::ShortRead {
+                expected: 4,
+                received: 2
+            })
+        ));
+
+        let mut read = endpoint(&[]);
+        assert!(matches!(receive_payload(&mut read), Err(SwitchError::Timeout)));
+    }
+
+    #[test]
+    fn oversized_payload_is_drained_not_allocated() {
+        let mut read = endpoint(&[&[0xff, 0xff, 0xff, 0xff], &[0; 16]]);
+        assert!(matches!(
+            receive_payload(&mut read),
+            Err(SwitchError::SizeMismatch {
+                expected: MAX_PAYLOAD_SIZE,
+                announced: 0xffff_ffff
+            })
+        ));
+    }
+}